@@ -63,7 +63,8 @@ function find(file = '') {
 export function encode(args) {
     // remove --dev so scode doesnt throw
     let nargs = [...args].filter(v => v !== "--dev")
-    const child = spawn(scode(), [...nargs, `--ffmpeg=${ffmpeg}`, '--yes=true'])
+    // own flags first, so they are not taken as arguments of a subcommand
+    const child = spawn(scode(), [`--ffmpeg=${ffmpeg}`, '--yes=true', ...nargs])
     child.stdout.on('data', (data) => {
         process.stdout.write(data.toString());
     })
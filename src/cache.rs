@@ -0,0 +1,152 @@
+// Inspects and repairs the cache of source items.
+// Entries are keyed by the absolute path of their source, an entry is stale
+// when that source no longer exists.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{CacheArgs, CacheCommand, Config},
+    info::{self, Item},
+    logging,
+};
+
+fn read(dir: &Path) -> io::Result<info::Map> {
+    info::Map::from_cache_bin(dir).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("There is no cache in {}", dir.to_string_lossy()),
+        ),
+        kind => io::Error::new(kind, format!("The cache can not be used, {e}")),
+    })
+}
+
+/// Keys of the entries whose source is gone, sorted.
+fn stale_keys(map: &info::Map) -> Vec<String> {
+    let mut keys: Vec<String> = map
+        .value
+        .iter()
+        .filter(|(key, item)| !Path::new(info::Map::source_of(key, item)).is_file())
+        .map(|(key, _)| key.clone())
+        .collect();
+    keys.sort();
+    keys
+}
+
+fn stats(dir: &Path) -> io::Result<()> {
+    let file = dir.join(info::CACHE_FILE);
+    let size = fs::metadata(&file)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    let map = read(dir)?;
+    info!(
+        "Cache: {} ({})",
+        file.to_string_lossy(),
+        logging::size(size)
+    );
+    let mut packages: Vec<(&str, usize)> = Vec::new();
+    for item in map.value.values() {
+        match packages
+            .iter_mut()
+            .find(|(package, _)| *package == item.package)
+        {
+            Some((_, count)) => *count += 1,
+            None => packages.push((&item.package, 1)),
+        }
+    }
+    packages.sort();
+    for (package, count) in &packages {
+        info!("  {package}: {count} sounds");
+    }
+    let missing_outputs = map
+        .value
+        .values()
+        .filter(|item| {
+            item.formats
+                .iter()
+                .any(|format| !item.output_path_for(*format).exists())
+        })
+        .count();
+    let stale = stale_keys(&map);
+    for key in &stale {
+        debug!("Stale: {key}");
+    }
+    info!("{} entries whose outputs are missing", missing_outputs);
+    if stale.is_empty() {
+        success!("{} entries, none stale", map.value.len());
+    } else {
+        warn!(
+            "{} entries, {} stale, run scode cache prune to remove them",
+            map.value.len(),
+            stale.len()
+        );
+    }
+    Ok(())
+}
+
+fn show(dir: &Path, path: &str) -> io::Result<()> {
+    let map = read(dir)?;
+    let Some(item) = map.get(path) else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No cache entry for file: {path}"),
+        ));
+    };
+    let json =
+        serde_json::to_string_pretty::<Item>(item).map_err(|e| io::Error::other(e.to_string()))?;
+    println!("{json}");
+    Ok(())
+}
+
+fn prune(dir: &Path, dry_run: bool) -> io::Result<()> {
+    let mut map = read(dir)?;
+    let stale = stale_keys(&map);
+    for key in &stale {
+        if dry_run {
+            info!("Would remove {key}");
+        } else {
+            info!("Removing {key}");
+            map.value.remove(key);
+        }
+    }
+    if dry_run {
+        success!(
+            "Would remove {} of {} entries",
+            stale.len(),
+            map.value.len()
+        );
+        return Ok(());
+    }
+    if !stale.is_empty() {
+        map.save_cache_bin(dir)?;
+    }
+    success!("Removed {} entries, {} left", stale.len(), map.value.len());
+    Ok(())
+}
+
+/// Removes the files scode writes to the cache directory, the directory itself is left alone.
+fn clear(dir: &Path) -> io::Result<()> {
+    let files: Vec<PathBuf> = [info::CACHE_FILE, info::CACHE_JSON_FILE]
+        .iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.exists())
+        .collect();
+    for file in &files {
+        info!("Removing {}", file.to_string_lossy());
+        fs::remove_file(file)?;
+    }
+    success!("Cleared the cache in {}", dir.to_string_lossy());
+    Ok(())
+}
+
+pub fn run(config: &Config, args: &CacheArgs) -> io::Result<()> {
+    let dir = config.cache_dir();
+    match args.command {
+        CacheCommand::Stats => stats(&dir),
+        CacheCommand::Show { ref path } => show(&dir, path),
+        CacheCommand::Prune { dry_run } => prune(&dir, dry_run.unwrap_or(false)),
+        CacheCommand::Clear => clear(&dir),
+    }
+}
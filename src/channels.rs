@@ -0,0 +1,221 @@
+// Channel layouts and downmixing.
+// Speaker positions follow the WAVE_FORMAT_EXTENSIBLE channel mask bits,
+// layout names follow the names ffmpeg uses.
+
+const FL: u32 = 0x1;
+const FR: u32 = 0x2;
+const FC: u32 = 0x4;
+const LFE: u32 = 0x8;
+const BL: u32 = 0x10;
+const BR: u32 = 0x20;
+const FLC: u32 = 0x40;
+const FRC: u32 = 0x80;
+const BC: u32 = 0x100;
+const SL: u32 = 0x200;
+const SR: u32 = 0x400;
+
+/// The most channels any of the output formats can carry,
+/// opus uses channel mapping family 1 which is limited to 8.
+pub const MAX_CHANNELS: u16 = 8;
+
+const LAYOUTS: [(&str, u32); 15] = [
+    ("mono", FC),
+    ("stereo", FL | FR),
+    ("2.1", FL | FR | LFE),
+    ("3.0", FL | FR | FC),
+    ("4.0", FL | FR | FC | BC),
+    ("quad", FL | FR | BL | BR),
+    ("quad(side)", FL | FR | SL | SR),
+    ("3.1", FL | FR | FC | LFE),
+    ("5.0", FL | FR | FC | SL | SR),
+    ("5.0(back)", FL | FR | FC | BL | BR),
+    ("5.1", FL | FR | FC | LFE | SL | SR),
+    ("5.1(back)", FL | FR | FC | LFE | BL | BR),
+    ("6.1", FL | FR | FC | LFE | BC | SL | SR),
+    ("7.1", FL | FR | FC | LFE | BL | BR | SL | SR),
+    ("7.1(wide)", FL | FR | FC | LFE | BL | BR | FLC | FRC),
+];
+
+/// The layout ffmpeg assumes for a channel count when the file does not say.
+fn default_mask(num_channels: u16) -> Option<u32> {
+    match num_channels {
+        1 => Some(FC),
+        2 => Some(FL | FR),
+        3 => Some(FL | FR | LFE),
+        4 => Some(FL | FR | FC | BC),
+        5 => Some(FL | FR | FC | SL | SR),
+        6 => Some(FL | FR | FC | LFE | SL | SR),
+        7 => Some(FL | FR | FC | LFE | BC | SL | SR),
+        8 => Some(FL | FR | FC | LFE | BL | BR | SL | SR),
+        _ => None,
+    }
+}
+
+/// The speaker mask of a source, taken from the wav channel mask when it
+/// matches the channel count, otherwise the default layout for the count.
+pub fn resolve_mask(num_channels: u16, channel_mask: u32) -> Option<u32> {
+    if channel_mask != 0 && channel_mask.count_ones() == u32::from(num_channels) {
+        return Some(channel_mask);
+    }
+    default_mask(num_channels)
+}
+
+/// Name of the layout for a speaker mask, as understood by ffmpeg filters.
+pub fn layout_name(mask: u32) -> Option<&'static str> {
+    LAYOUTS
+        .iter()
+        .find(|(_, layout)| *layout == mask)
+        .map(|(name, _)| *name)
+}
+
+/// Name of the layout used for an output with the given number of channels.
+pub fn output_layout_name(num_channels: u16) -> String {
+    default_mask(num_channels)
+        .and_then(layout_name)
+        .map_or_else(|| format!("{num_channels}c"), str::to_string)
+}
+
+/// Gains for a single input speaker when mixing down to stereo.
+/// Centered speakers are split at -3dB and the lfe is dropped.
+fn stereo_gains(speaker: u32) -> (f32, f32) {
+    const HALF_POWER: f32 = std::f32::consts::FRAC_1_SQRT_2;
+    match speaker {
+        FL | FLC | BL | SL => (1.0, 0.0),
+        FR | FRC | BR | SR => (0.0, 1.0),
+        FC | BC => (HALF_POWER, HALF_POWER),
+        _ => (0.0, 0.0),
+    }
+}
+
+/// Scales a row so that the gains sum to one, which keeps the mix from clipping.
+fn normalize(row: Vec<f32>) -> Vec<f32> {
+    let sum: f32 = row.iter().sum();
+    if sum <= 0.0 {
+        return row;
+    }
+    row.into_iter().map(|gain| gain / sum).collect()
+}
+
+/// The matrix used when no downmix is configured for a source.
+/// Rows are output channels and columns are input channels.
+/// Returns `None` when the channel count is unchanged or when no sensible
+/// default exists, in which case ffmpeg decides.
+pub fn default_downmix(
+    input_channels: u16,
+    mask: Option<u32>,
+    target_channels: u16,
+) -> Option<Vec<Vec<f32>>> {
+    if target_channels >= input_channels || target_channels > 2 {
+        return None;
+    }
+    let inputs = usize::from(input_channels);
+    let Some(mask) = mask else {
+        // unknown speaker positions, use an equal mix of every channel
+        let row = normalize(vec![1.0; inputs]);
+        return Some(vec![row; usize::from(target_channels)]);
+    };
+    // speakers in the order they appear in the file
+    let speakers: Vec<u32> = (0..32)
+        .map(|bit| 1 << bit)
+        .filter(|bit| mask & bit != 0)
+        .collect();
+    let (left, right): (Vec<f32>, Vec<f32>) = speakers
+        .iter()
+        .map(|speaker| stereo_gains(*speaker))
+        .unzip();
+    if target_channels == 2 {
+        return Some(vec![normalize(left), normalize(right)]);
+    }
+    let mono = left.iter().zip(right.iter()).map(|(l, r)| l + r).collect();
+    Some(vec![normalize(mono)])
+}
+
+/// Checks that a source with `input_channels` can be encoded to `target_channels`,
+/// using the given downmix matrix if there is one.
+/// Without a matrix ffmpeg converts the channel layout, upmixing included.
+pub fn validate(
+    input_channels: u16,
+    target_channels: u16,
+    downmix: Option<&Vec<Vec<f32>>>,
+) -> Result<(), String> {
+    if target_channels == 0 {
+        return Err("Target channels can not be 0".to_string());
+    }
+    if target_channels > MAX_CHANNELS {
+        return Err(format!(
+            "Target channels {target_channels} is more than the supported {MAX_CHANNELS}"
+        ));
+    }
+    let Some(matrix) = downmix else {
+        return Ok(());
+    };
+    if matrix.len() != usize::from(target_channels) {
+        return Err(format!(
+            "Downmix matrix has {} rows but the target has {target_channels} channels",
+            matrix.len()
+        ));
+    }
+    if let Some(row) = matrix
+        .iter()
+        .find(|row| row.len() != usize::from(input_channels))
+    {
+        return Err(format!(
+            "Downmix matrix row has {} columns but the source has {input_channels} channels",
+            row.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Creates an ffmpeg pan filter from a downmix matrix,
+/// for example `pan=mono|c0=0.5*c0+0.5*c1`.
+pub fn pan_filter(matrix: &[Vec<f32>]) -> String {
+    let layout = output_layout_name(matrix.len() as u16);
+    let outputs = matrix
+        .iter()
+        .enumerate()
+        .map(|(output, row)| {
+            let terms = row
+                .iter()
+                .enumerate()
+                .filter(|(_, gain)| **gain != 0.0)
+                .map(|(input, gain)| format!("{gain}*c{input}"))
+                .collect::<Vec<String>>();
+            if terms.is_empty() {
+                format!("c{output}=0*c0")
+            } else {
+                format!("c{output}={}", terms.join("+"))
+            }
+        })
+        .collect::<Vec<String>>();
+    format!("pan={layout}|{}", outputs.join("|"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stereo_to_mono_matches_the_old_filter() {
+        let matrix = default_downmix(2, resolve_mask(2, 0), 1).unwrap();
+        assert_eq!(pan_filter(&matrix), "pan=mono|c0=0.5*c0+0.5*c1");
+    }
+
+    #[test]
+    fn surround_to_stereo_drops_the_lfe() {
+        let matrix = default_downmix(6, resolve_mask(6, 0), 2).unwrap();
+        assert_eq!(matrix.len(), 2);
+        assert_eq!(matrix[0][3], 0.0);
+        assert_eq!(matrix[1][3], 0.0);
+        assert!((matrix[0].iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_impossible_targets() {
+        assert!(validate(2, 0, None).is_err());
+        assert!(validate(2, 6, None).is_ok());
+        assert!(validate(6, 9, None).is_err());
+        assert!(validate(2, 1, Some(&vec![vec![1.0]])).is_err());
+        assert!(validate(1, 2, Some(&vec![vec![1.0], vec![1.0]])).is_ok());
+    }
+}
@@ -0,0 +1,327 @@
+// Checks a config beyond what parsing does, for `scode check-config` and before every run.
+// Errors stop the run, warnings point at settings that probably do not do what was meant.
+// Problems are reported with the path of the key, like `packages.ui.sources.click.bitrate`.
+
+use std::{collections::HashSet, io, ops::RangeInclusive};
+
+use crate::{
+    channels,
+    config::{Config, Package, Source},
+    discover,
+    format::Format,
+    naming, sources,
+};
+
+/// Bitrates are per channel, opus does not go below or above these.
+const BITRATES: RangeInclusive<u32> = 6..=256;
+/// The widest range of any format, each format checks its own when encoding.
+const SAMPLE_RATES: RangeInclusive<u32> = 8000..=192_000;
+const OGG_QUALITIES: RangeInclusive<f32> = -1.0..=10.0;
+
+#[derive(Default)]
+pub struct Problems {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Problems {
+    fn check_bitrate(&mut self, key: &str, bitrate: Option<u32>) {
+        if let Some(bitrate) = bitrate.filter(|bitrate| !BITRATES.contains(bitrate)) {
+            self.errors.push(format!(
+                "{key}: {bitrate} is outside {} to {} kbps per channel",
+                BITRATES.start(),
+                BITRATES.end()
+            ));
+        }
+    }
+
+    fn check_sample_rate(&mut self, key: &str, sample_rate: Option<u32>) {
+        if let Some(sample_rate) = sample_rate.filter(|rate| !SAMPLE_RATES.contains(rate)) {
+            self.errors.push(format!(
+                "{key}: {sample_rate} is outside {} to {} Hz",
+                SAMPLE_RATES.start(),
+                SAMPLE_RATES.end()
+            ));
+        }
+    }
+
+    fn check_formats_list(&mut self, key: &str, formats: Option<&Vec<Format>>) {
+        if formats.is_some_and(Vec::is_empty) {
+            self.errors
+                .push(format!("{key}: is empty, so nothing would be encoded"));
+        }
+    }
+
+    /// The sample rate against the formats it is encoded to, only checked where
+    /// either of them changes, so a problem is reported once at the level causing it.
+    fn check_formats(
+        &mut self,
+        key: &str,
+        resolved: (u32, &[Format]),
+        parent: Option<(u32, &[Format])>,
+    ) {
+        let (sample_rate, formats) = resolved;
+        if parent == Some(resolved) || !SAMPLE_RATES.contains(&sample_rate) {
+            return;
+        }
+        for format in formats
+            .iter()
+            .filter(|format| !format.supports_sample_rate(sample_rate))
+        {
+            self.errors.push(format!(
+                "{key}: {format} can not be encoded at {sample_rate} Hz"
+            ));
+        }
+    }
+
+    /// The sample rates of a resolved package and its sources against their formats.
+    fn check_package_formats(&mut self, name: &str, package: &Package, global: (u32, &[Format])) {
+        let key = format!("packages.{name}");
+        let sample_rate = package.samplerate.unwrap_or(global.0);
+        let formats = package.formats(global.1);
+        self.check_formats(&key, (sample_rate, &formats), Some(global));
+        let mut sources: Vec<(&String, &Source)> = package.sources.iter().flatten().collect();
+        sources.sort_by_key(|(source_name, _)| *source_name);
+        for (source_name, source) in sources {
+            self.check_formats(
+                &format!("{key}.sources.{source_name}"),
+                (
+                    source.samplerate.unwrap_or(sample_rate),
+                    &source.formats(&formats),
+                ),
+                Some((sample_rate, &formats)),
+            );
+        }
+    }
+
+    fn check_source(&mut self, key: &str, source: &Source) {
+        self.check_bitrate(&format!("{key}.bitrate"), source.bitrate);
+        self.check_sample_rate(&format!("{key}.samplerate"), source.samplerate);
+        self.check_formats_list(&format!("{key}.formats"), source.formats.as_ref());
+        if let Some(channels) = source
+            .channels
+            .filter(|channels| !(1..=channels::MAX_CHANNELS).contains(channels))
+        {
+            self.errors.push(format!(
+                "{key}.channels: {channels} is outside 1 to {}",
+                channels::MAX_CHANNELS
+            ));
+        }
+    }
+
+    /// The settings of a package as written in the config.
+    fn check_package(&mut self, name: &str, package: &Package) {
+        let key = format!("packages.{name}");
+        self.check_bitrate(&format!("{key}.bitrate"), package.bitrate);
+        self.check_sample_rate(&format!("{key}.samplerate"), package.samplerate);
+        self.check_formats_list(&format!("{key}.formats"), package.formats.as_ref());
+        let mut sources: Vec<(&String, &Source)> = package.sources.iter().flatten().collect();
+        sources.sort_by_key(|(source_name, _)| *source_name);
+        for (source_name, source) in sources {
+            self.check_source(&format!("{key}.sources.{source_name}"), source);
+        }
+        if package.max_depth.is_some() && package.recursive != Some(true) {
+            self.warnings.push(format!(
+                "{key}.max_depth: only used together with \"recursive\": true"
+            ));
+        }
+    }
+
+    /// The sounds of a resolved package against the `sources` it declares itself.
+    fn check_sounds(&mut self, config: &Config, name: &str, package: &Package, declared: &Package) {
+        let langs: Vec<(&str, Option<&str>)> = match package.languages {
+            Some(ref languages) => languages
+                .iter()
+                .map(|(lang, dir)| (lang.as_str(), Some(dir.as_str())))
+                .collect(),
+            None => vec![(crate::NO_LANG, None)],
+        };
+        let mut names: HashSet<String> = HashSet::new();
+        for (lang, lang_dir) in langs {
+            match discover::source_files(config, name, package, lang, lang_dir) {
+                Ok(files) => names.extend(files.into_iter().map(|file| file.name)),
+                Err(e) => {
+                    self.errors.push(format!("packages.{name}: {e}"));
+                    return;
+                }
+            }
+        }
+        let mut missing: Vec<&String> = declared
+            .sources
+            .iter()
+            .flat_map(|sources| sources.keys())
+            .filter(|key| !sources::is_pattern(key) && !names.contains(*key))
+            .collect();
+        missing.sort();
+        for key in missing {
+            self.errors.push(format!(
+                "packages.{name}.sources.{key}: there is no sound {key}"
+            ));
+        }
+        match sources::Sources::new(name, declared.sources.as_ref()) {
+            Ok(sources) => {
+                for sound in &names {
+                    sources.get(sound);
+                }
+                for key in sources.unmatched() {
+                    self.warnings
+                        .push(format!("packages.{name}.sources.{key}: matches no sound"));
+                }
+            }
+            Err(e) => self.errors.push(format!("packages.{name}: {e}")),
+        }
+    }
+}
+
+/// The problems of `declared`, the config as written, and of `config`, the config
+/// with `extends` resolved and the command line applied.
+/// Global settings are checked in `config`, so values from the command line are checked too.
+pub fn problems(declared: &Config, config: &Config) -> Problems {
+    let mut problems = Problems::default();
+    problems.check_bitrate("bitrate", Some(config.bitrate));
+    problems.check_sample_rate("samplerate", config.samplerate);
+    if let Some(quality) = config
+        .ogg_quality
+        .filter(|quality| !OGG_QUALITIES.contains(quality))
+    {
+        problems.errors.push(format!(
+            "ogg_quality: {quality} is outside {} to {}",
+            OGG_QUALITIES.start(),
+            OGG_QUALITIES.end()
+        ));
+    }
+    if let Err(e) = naming::Template::from_config(config) {
+        problems.errors.push(format!("output_name: {e}"));
+    }
+    let sample_rate = config.samplerate.unwrap_or(crate::DEFAULT_SAMPLE_RATE);
+    let formats = config.formats();
+    if config.formats.is_none() && formats.is_empty() {
+        problems.errors.push(
+            "formats: not set and every include_* flag is off, so nothing would be encoded"
+                .to_string(),
+        );
+    }
+    problems.check_formats_list("formats", config.formats.as_ref());
+    problems.check_formats("samplerate", (sample_rate, &formats), None);
+    let mut names: Vec<&String> = config.packages.keys().collect();
+    names.sort();
+    for name in names {
+        let Some(declared_package) = declared.packages.get(name) else {
+            continue;
+        };
+        problems.check_package(name, declared_package);
+        problems.check_package_formats(name, &config.packages[name], (sample_rate, &formats));
+        problems.check_sounds(config, name, &config.packages[name], declared_package);
+    }
+    problems
+}
+
+/// Logs the errors of the config and fails with `Unsupported` when there are any.
+pub fn require_valid(declared: &Config, config: &Config) -> io::Result<()> {
+    let problems = problems(declared, config);
+    for error in &problems.errors {
+        error!("{error}");
+    }
+    if problems.errors.is_empty() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "The config has {} errors, run scode check-config to see all problems",
+            problems.errors.len()
+        ),
+    ))
+}
+
+/// `scode check-config`, lists every problem of the config.
+pub fn run(declared: &Config, config: &Config, config_path: &str) -> io::Result<()> {
+    let problems = problems(declared, config);
+    for warning in &problems.warnings {
+        warn!("{warning}");
+    }
+    for error in &problems.errors {
+        error!("{error}");
+    }
+    if !problems.errors.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{config_path} has {} errors", problems.errors.len()),
+        ));
+    }
+    success!(
+        "{config_path} is valid, with {} warnings",
+        problems.warnings.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_values_out_of_range() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "indir": "in",
+                "outdir": "out",
+                "bitrate": 1000,
+                "packages": {"ui": {"sources": {"click": {"channels": 12, "samplerate": 100}}}}
+            }"#,
+        )
+        .unwrap();
+        let mut problems = Problems::default();
+        problems.check_bitrate("bitrate", Some(config.bitrate));
+        problems.check_package("ui", &config.packages["ui"]);
+        assert_eq!(problems.errors.len(), 3);
+        assert!(problems.errors[1].starts_with("packages.ui.sources.click.samplerate"));
+    }
+
+    #[test]
+    fn reports_sample_rates_the_formats_can_not_encode() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "indir": "in",
+                "outdir": "out",
+                "bitrate": 96,
+                "formats": ["mp4"],
+                "samplerate": 44100,
+                "packages": {"ui": {"sources": {"click": {"samplerate": 37800}}}}
+            }"#,
+        )
+        .unwrap();
+        let mut problems = Problems::default();
+        let formats = config.formats();
+        problems.check_package_formats("ui", &config.packages["ui"], (44100, &formats));
+        assert_eq!(
+            problems.errors,
+            ["packages.ui.sources.click: mp4 can not be encoded at 37800 Hz"]
+        );
+    }
+
+    #[test]
+    fn reports_empty_format_lists() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "indir": "in",
+                "outdir": "out",
+                "bitrate": 96,
+                "formats": [],
+                "packages": {"ui": {"formats": [], "sources": {"click": {"formats": []}}}}
+            }"#,
+        )
+        .unwrap();
+        let errors = problems(&config, &config).errors;
+        for key in [
+            "formats",
+            "packages.ui.formats",
+            "packages.ui.sources.click.formats",
+        ] {
+            assert!(
+                errors.contains(&format!("{key}: is empty, so nothing would be encoded")),
+                "{errors:?}"
+            );
+        }
+    }
+}
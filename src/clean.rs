@@ -0,0 +1,139 @@
+// Removes outputs that no sound refers to anymore.
+// Only files with the extension of an output format are ever touched,
+// the atlas and anything matching a keep pattern are left alone.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    config::{CleanArgs, Config},
+    format::Format,
+    info::Item,
+    logging, plan,
+    sprite::Sprite,
+};
+
+const OUTPUT_FORMATS: [Format; 5] = [
+    Format::Webm,
+    Format::Opus,
+    Format::Mp4,
+    Format::Flac,
+    Format::Ogg,
+];
+
+/// Whether the file has the extension of one of the formats scode writes.
+fn is_output(path: &Path) -> bool {
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    OUTPUT_FORMATS
+        .iter()
+        .any(|format| format.extension() == extension)
+}
+
+fn is_old_enough(path: &Path, min_age: Option<f64>) -> io::Result<bool> {
+    let Some(hours) = min_age else {
+        return Ok(true);
+    };
+    let modified = fs::metadata(path)?.modified()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    Ok(age.as_secs_f64() >= hours * 3600.0)
+}
+
+/// Removes the directories above `path` that became empty, up to `outdir`.
+fn remove_empty_parents(path: &Path, outdir: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == outdir || !current.starts_with(outdir) || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+/// Lists or removes the orphaned outputs in `outdir`.
+/// `items` and `sprites` must cover every package, otherwise the outputs
+/// of the missing ones would be removed.
+pub fn run(
+    config: &Config,
+    args: &CleanArgs,
+    items: &[Item],
+    sprites: &[Sprite],
+) -> io::Result<()> {
+    let settings = config.clean.clone().unwrap_or_default();
+    let dry_run = args.dry_run.or(config.dry_run).unwrap_or(false);
+    let min_age = args.min_age.or(settings.min_age);
+    let keep = settings
+        .keep
+        .unwrap_or_default()
+        .iter()
+        .chain(args.keep.iter())
+        .map(|pattern| {
+            glob::Pattern::new(pattern).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Invalid keep pattern {pattern}: {e}"),
+                )
+            })
+        })
+        .collect::<io::Result<Vec<glob::Pattern>>>()?;
+
+    let outdir = Path::new(&config.outdir).canonicalize()?;
+    let mut removable: Vec<(PathBuf, u64)> = Vec::new();
+    for path in plan::orphans(&config.outdir, items, sprites)? {
+        if !is_output(&path) {
+            debug!("Not an output, keeping {}", path.to_string_lossy());
+            continue;
+        }
+        let relative = path
+            .strip_prefix(&outdir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        if keep.iter().any(|pattern| pattern.matches(&relative)) {
+            debug!("Keeping {relative}");
+            continue;
+        }
+        if !is_old_enough(&path, min_age)? {
+            debug!("Too recent, keeping {relative}");
+            continue;
+        }
+        let size = fs::metadata(&path)?.len();
+        removable.push((path, size));
+    }
+
+    let total: u64 = removable.iter().map(|(_, size)| size).sum();
+    if dry_run {
+        for (path, size) in &removable {
+            info!(
+                "Would remove {} ({})",
+                path.to_string_lossy(),
+                logging::size(*size)
+            );
+        }
+        success!(
+            "Would remove {} files, {}",
+            removable.len(),
+            logging::size(total)
+        );
+        return Ok(());
+    }
+    for (path, size) in &removable {
+        info!(
+            "Removing {} ({})",
+            path.to_string_lossy(),
+            logging::size(*size)
+        );
+        fs::remove_file(path)?;
+        remove_empty_parents(path, &outdir);
+    }
+    success!(
+        "Removed {} files, {}",
+        removable.len(),
+        logging::size(total)
+    );
+    Ok(())
+}
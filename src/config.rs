@@ -1,9 +1,22 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs::File, io::Read, path::Path};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::format::{self, Format};
+
+/// Cache directory, relative to the config file.
+const DEFAULT_CACHE_DIR: &str = ".cache";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Editors read the schema from here, scode does not.
+    #[serde(rename = "$schema")]
+    pub schema: Option<String>,
     pub indir: String,
     pub outdir: String,
     pub bitrate: u32,
@@ -11,95 +24,385 @@ pub struct Config {
     pub loglevel: Option<String>,
     pub packages: HashMap<String, Package>,
     pub ffmpeg: Option<String>,
+    pub ffprobe: Option<String>,
     pub include_webm: Option<bool>,
     pub include_opus: Option<bool>,
     pub include_mp4: Option<bool>,
     pub include_flac: Option<bool>,
+    pub include_ogg: Option<bool>,
+    pub ogg_quality: Option<f32>,
+    pub formats: Option<Vec<Format>>,
+    pub samplerate: Option<u32>,
+    pub jobs: Option<usize>,
+    pub scan_jobs: Option<usize>,
+    pub threads: Option<u32>,
+    pub max_memory: Option<u64>,
+    pub timeout: Option<f64>,
+    pub timeout_factor: Option<f64>,
+    pub retries: Option<u32>,
+    pub retry_backoff: Option<u64>,
+    pub verify: Option<bool>,
+    pub verify_tolerance: Option<u32>,
+    pub output_name: Option<String>,
     pub use_cache: Option<bool>,
+    /// Relative to the config file when set in it.
+    pub cache_dir: Option<String>,
+    pub clean: Option<Clean>,
+    pub store: Option<Store>,
+    /// Wait for another run to finish instead of failing.
+    pub wait: Option<bool>,
+    /// Only report what would be encoded, only set from the command line.
+    #[serde(skip)]
+    pub dry_run: Option<bool>,
+    /// Where to write the dry run report as json, only set from the command line.
+    #[serde(skip)]
+    pub plan_json: Option<String>,
+    /// Whether `--packages` limits the run to some of the packages.
+    #[serde(skip)]
+    pub partial: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Package {
     pub sourcedir: Option<String>,
     pub bitrate: Option<u32>,
+    /// Packages whose settings this one inherits, later ones override earlier ones.
     pub extends: Option<Vec<String>>,
+    /// Whether the sounds of the extended packages are encoded for this one too.
+    pub inherit_sounds: Option<bool>,
     pub languages: Option<HashMap<String, String>>,
     pub sources: Option<HashMap<String, Source>>,
     pub include_flac: Option<bool>,
+    pub formats: Option<Vec<Format>>,
+    pub samplerate: Option<u32>,
+    pub sprite: Option<Sprite>,
+    /// Whether sounds are also read from subdirectories, named by their relative path.
+    pub recursive: Option<bool>,
+    /// Levels of subdirectories read when recursive, unlimited by default.
+    pub max_depth: Option<usize>,
+    /// Glob patterns of files and directories to leave out, relative to the
+    /// source or language directory.
+    pub ignore: Option<Vec<String>>,
+    pub author: Option<String>,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Sound directories inherited through `extends`, resolved by `Config::resolve_extends`.
+    #[serde(skip)]
+    pub inherited_sounds: Vec<SoundLayer>,
+}
+
+/// The sounds of an extended package, with the layout they have in that package.
+#[derive(Debug, Clone)]
+pub struct SoundLayer {
+    pub package: String,
+    pub sourcedir: String,
+    pub languages: Option<HashMap<String, String>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Sprite {
+    /// Sources up to this many seconds long are put in the sprite.
+    pub max_duration: Option<f64>,
+    /// Minimum silence between two sounds in milliseconds.
+    pub gap: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Clean {
+    /// Glob patterns relative to outdir of files that are never removed.
+    pub keep: Option<Vec<String>>,
+    /// Only files last modified at least this many hours ago are removed.
+    pub min_age: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Store {
+    /// A directory, or the url of a bucket on an S3 compatible endpoint.
+    pub location: Option<String>,
+    /// Region used to sign S3 requests.
+    pub region: Option<String>,
+    /// Whether outputs encoded by this run are uploaded, readers like CI can turn it off.
+    pub publish: Option<bool>,
+    /// Path of curl, used for S3.
+    pub curl: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Source {
     pub bitrate: Option<u32>,
     pub channels: Option<u16>,
+    pub formats: Option<Vec<Format>>,
+    pub samplerate: Option<u32>,
+    /// Mix matrix with one row of gains per output channel and one column per input channel.
+    pub downmix: Option<Vec<Vec<f32>>>,
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
-    // Add optional command line arguments to override JSON configuration
-    #[clap(long)]
+    // Add optional command line arguments to override JSON configuration,
+    // global so they can also be given after a subcommand
+    #[clap(long, global = true)]
     pub config: Option<String>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub indir: Option<String>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub outdir: Option<String>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub bitrate: Option<u32>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub yes: Option<bool>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub loglevel: Option<String>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub packages: Option<Vec<String>>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub ffmpeg: Option<String>,
-    #[clap(long)]
+    /// Path of ffprobe, defaults to the ffprobe next to ffmpeg
+    #[clap(long, global = true)]
+    pub ffprobe: Option<String>,
+    #[clap(long, global = true)]
     pub include_opus: Option<bool>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub include_webm: Option<bool>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub include_mp4: Option<bool>,
-    #[clap(long)]
+    #[clap(long, global = true)]
     pub include_flac: Option<bool>,
-    #[clap(long)]
+    #[clap(long, global = true)]
+    pub include_ogg: Option<bool>,
+    #[clap(long, global = true)]
+    pub ogg_quality: Option<f32>,
+    #[clap(long, global = true, value_enum, value_delimiter = ',')]
+    pub formats: Option<Vec<Format>>,
+    #[clap(long, global = true)]
+    pub samplerate: Option<u32>,
+    /// Number of encoder processes to run at once, defaults to the number of cores
+    #[clap(long, global = true)]
+    pub jobs: Option<usize>,
+    /// Number of files to scan at once, defaults to the number of jobs
+    #[clap(long, global = true)]
+    pub scan_jobs: Option<usize>,
+    /// Number of threads each encoder process may use
+    #[clap(long, global = true)]
+    pub threads: Option<u32>,
+    /// Only start encoder processes while their estimated memory stays below this many megabytes
+    #[clap(long, global = true)]
+    pub max_memory: Option<u64>,
+    /// Seconds every encoder process gets before it is killed as hung
+    #[clap(long, global = true)]
+    pub timeout: Option<f64>,
+    /// Extra seconds an encoder process gets per second of source audio
+    #[clap(long, global = true)]
+    pub timeout_factor: Option<f64>,
+    /// Number of times an encoder process killed by a signal or that could not start is retried
+    #[clap(long, global = true)]
+    pub retries: Option<u32>,
+    /// Milliseconds to wait before the first retry, doubled for every following retry
+    #[clap(long, global = true)]
+    pub retry_backoff: Option<u64>,
+    /// Probe every encoded output and fail when it does not match its settings
+    #[clap(long, global = true)]
+    pub verify: Option<bool>,
+    /// Allowed difference between the duration of an output and its source in milliseconds
+    #[clap(long, global = true)]
+    pub verify_tolerance: Option<u32>,
+    /// Template of the output file names, like {package}/{lang}/{name}.{ext}
+    #[clap(long, global = true)]
+    pub output_name: Option<String>,
+    #[clap(long, global = true)]
     pub use_cache: Option<bool>,
+    /// Directory of the cache, defaults to .cache next to the config file
+    #[clap(long, global = true, env = "SCODE_CACHE_DIR")]
+    pub cache_dir: Option<String>,
+    /// Report what would be encoded and why, without encoding or writing the cache and atlas
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub dry_run: Option<bool>,
+    /// Also write the dry run report as json to this file
+    #[clap(long, global = true)]
+    pub plan_json: Option<String>,
+    /// Shared store of encoded outputs, a directory or the url of an S3 bucket
+    #[clap(long, global = true, env = "SCODE_STORE")]
+    pub store: Option<String>,
+    /// Whether to upload the outputs encoded by this run to the store
+    #[clap(long, global = true)]
+    pub store_publish: Option<bool>,
+    /// Wait for another scode run on the same cache to finish instead of failing
+    #[clap(long, global = true, num_args = 0..=1, default_missing_value = "true")]
+    pub wait: Option<bool>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Remove outputs in outdir that no sound refers to anymore
+    Clean(CleanArgs),
+    /// Encode, then keep encoding the sources that change until stopped
+    Watch(WatchArgs),
+    /// Inspect or repair the cache
+    Cache(CacheArgs),
+    /// Check the config and the package directories without encoding
+    CheckConfig,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CacheArgs {
+    #[clap(subcommand)]
+    pub command: CacheCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Report the size of the cache, its entries per package and the stale ones
+    Stats,
+    /// Print the cache entry of a source as json
+    Show {
+        /// Path of the source wav file
+        path: String,
+    },
+    /// Remove the entries of sources that no longer exist
+    Prune {
+        /// Only list the entries that would be removed
+        #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+        dry_run: Option<bool>,
+    },
+    /// Remove the cache files
+    Clear,
+}
+
+impl CacheCommand {
+    /// Whether the command changes the cache.
+    pub fn writes(&self) -> bool {
+        matches!(self, CacheCommand::Prune { dry_run } if !dry_run.unwrap_or(false))
+            || matches!(self, CacheCommand::Clear)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CleanArgs {
+    /// Only list the files that would be removed
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub dry_run: Option<bool>,
+    /// Only remove files last modified at least this many hours ago
+    #[clap(long)]
+    pub min_age: Option<f64>,
+    /// Never remove files matching this glob pattern, relative to outdir. Can be repeated
+    #[clap(long)]
+    pub keep: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+    /// Milliseconds to wait for more changes before encoding
+    #[clap(long, default_value_t = 300)]
+    pub debounce: u64,
+    /// Poll for changes instead of using file system events, for network drives and containers
+    #[clap(long, num_args = 0..=1, default_missing_value = "true")]
+    pub poll: Option<bool>,
 }
 
 impl Config {
-    pub fn load(config_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Directory the cache is read from and written to.
+    pub fn cache_dir(&self) -> PathBuf {
+        PathBuf::from(self.cache_dir.as_deref().unwrap_or(DEFAULT_CACHE_DIR))
+    }
+
+    /// Reads the config, failing with `InvalidData` and the line and column of the
+    /// problem when it is not valid json or has keys scode does not know.
+    pub fn load(config_path: &str) -> io::Result<Self> {
         let path = Path::new(config_path);
-        let mut file = File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let contents = strip_jsonc_comments(&contents, false);
-        let config: Config = serde_json::from_str(&contents)?;
+        let contents = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Could not read the config {config_path}: {e}"),
+            )
+        })?;
+        // comments become spaces, so serde reports locations in the original file
+        let json = strip_jsonc_comments(&contents, true);
+        let mut config: Config =
+            serde_json::from_str(&json).map_err(|e| parse_error(config_path, &contents, &e))?;
+        let dir = path.parent().unwrap_or(Path::new("")).to_string_lossy();
+        let cache_dir = config.cache_dir.as_deref().unwrap_or(DEFAULT_CACHE_DIR);
+        config.cache_dir = Some(join_path(&dir, cache_dir));
         Ok(config)
     }
+    /// Applies `extends` to every package, so the rest of scode only sees resolved packages.
+    /// Has to run before packages are filtered, as a package can extend one that is not built.
+    pub fn resolve_extends(&mut self) -> io::Result<()> {
+        let mut resolved: HashMap<String, (Package, Vec<SoundLayer>)> = HashMap::new();
+        let mut names: Vec<&String> = self.packages.keys().collect();
+        names.sort();
+        for name in names {
+            resolve_package(name, &self.packages, &mut resolved, &mut Vec::new())?;
+        }
+        self.packages = resolved
+            .into_iter()
+            .map(|(name, (package, _))| (name, package))
+            .collect();
+        Ok(())
+    }
     pub fn merge_with_args(self, args: Args) -> Self {
         Config {
+            schema: self.schema,
             indir: join_path(&args.indir.unwrap_or_default(), &self.indir),
             outdir: join_path(&args.outdir.unwrap_or_default(), &self.outdir),
             bitrate: args.bitrate.unwrap_or(self.bitrate),
             yes: args.yes.or(self.yes),
             loglevel: args.loglevel.or(self.loglevel),
+            partial: args.packages.is_some(),
             // filter packages by command line arguments
             packages: match args.packages {
                 Some(packages) => self
                     .packages
                     .into_iter()
-                    .filter(|(k, _)| {
-                        packages.contains(k)
-                    })
+                    .filter(|(k, _)| packages.contains(k))
                     .collect(),
                 None => self.packages,
             },
             ffmpeg: args.ffmpeg.or(self.ffmpeg),
+            ffprobe: args.ffprobe.or(self.ffprobe),
             include_webm: args.include_webm.or(self.include_webm).or(Some(true)),
             include_opus: args.include_opus.or(self.include_opus).or(Some(false)),
             include_mp4: args.include_mp4.or(self.include_mp4).or(Some(false)),
             include_flac: args.include_flac.or(self.include_flac).or(Some(false)),
+            include_ogg: args.include_ogg.or(self.include_ogg).or(Some(false)),
+            ogg_quality: args.ogg_quality.or(self.ogg_quality),
+            formats: args.formats.or(self.formats),
+            samplerate: args.samplerate.or(self.samplerate),
+            jobs: args.jobs.or(self.jobs),
+            scan_jobs: args.scan_jobs.or(self.scan_jobs),
+            timeout: args.timeout.or(self.timeout),
+            timeout_factor: args.timeout_factor.or(self.timeout_factor),
+            retries: args.retries.or(self.retries),
+            retry_backoff: args.retry_backoff.or(self.retry_backoff),
+            verify: args.verify.or(self.verify),
+            verify_tolerance: args.verify_tolerance.or(self.verify_tolerance),
+            output_name: args.output_name.or(self.output_name),
+            threads: args.threads.or(self.threads),
+            max_memory: args.max_memory.or(self.max_memory),
             use_cache: args.use_cache.or(self.use_cache),
+            cache_dir: args.cache_dir.or(self.cache_dir),
+            clean: self.clean,
+            store: match (args.store, args.store_publish) {
+                (None, None) => self.store,
+                (location, publish) => {
+                    let store = self.store.unwrap_or_default();
+                    Some(Store {
+                        location: location.or(store.location),
+                        publish: publish.or(store.publish),
+                        ..store
+                    })
+                }
+            },
+            wait: args.wait.or(self.wait),
+            dry_run: args.dry_run.or(self.dry_run),
+            plan_json: args.plan_json.or(self.plan_json),
         }
     }
 }
@@ -107,6 +410,7 @@ impl Config {
 impl std::default::Default for Config {
     fn default() -> Self {
         Config {
+            schema: None,
             indir: "packages".to_string(),
             outdir: "encoded".to_string(),
             bitrate: 96,
@@ -114,11 +418,34 @@ impl std::default::Default for Config {
             loglevel: None,
             packages: HashMap::new(),
             ffmpeg: Some("ffmpeg".to_string()),
+            ffprobe: None,
             include_webm: Some(true),
             include_opus: Some(false),
             include_mp4: Some(false),
             use_cache: Some(false),
-            include_flac: Some(false)
+            cache_dir: None,
+            include_flac: Some(false),
+            include_ogg: Some(false),
+            ogg_quality: None,
+            formats: None,
+            samplerate: None,
+            jobs: None,
+            scan_jobs: None,
+            timeout: None,
+            timeout_factor: None,
+            retries: None,
+            retry_backoff: None,
+            threads: None,
+            max_memory: None,
+            verify: None,
+            verify_tolerance: None,
+            output_name: None,
+            clean: None,
+            store: None,
+            wait: None,
+            dry_run: None,
+            plan_json: None,
+            partial: false,
         }
     }
 }
@@ -132,6 +459,9 @@ impl fmt::Display for Config {
         writeln!(f, "Input Directory: {}", self.indir)?;
         writeln!(f, "Output Directory: {}", self.outdir)?;
         writeln!(f, "Bitrate: {} kbps", self.bitrate)?;
+        if let Some(samplerate) = self.samplerate {
+            writeln!(f, "Sample Rate: {samplerate} Hz")?;
+        }
         if let Some(yes) = self.yes {
             writeln!(
                 f,
@@ -142,6 +472,51 @@ impl fmt::Display for Config {
         if let Some(ref loglevel) = self.loglevel {
             writeln!(f, "Log Level: {loglevel}")?;
         }
+        writeln!(f, "Formats: {:?}", self.formats())?;
+        if let Some(jobs) = self.jobs {
+            writeln!(f, "Jobs: {jobs}")?;
+        }
+        if let Some(scan_jobs) = self.scan_jobs {
+            writeln!(f, "Scan Jobs: {scan_jobs}")?;
+        }
+        if let Some(threads) = self.threads {
+            writeln!(f, "Threads per Job: {threads}")?;
+        }
+        if let Some(max_memory) = self.max_memory {
+            writeln!(f, "Max Memory: {max_memory} MB")?;
+        }
+        if let Some(timeout) = self.timeout {
+            writeln!(f, "Timeout: {timeout}s")?;
+        }
+        if let Some(timeout_factor) = self.timeout_factor {
+            writeln!(f, "Timeout per Second of Audio: {timeout_factor}s")?;
+        }
+        if let Some(retries) = self.retries {
+            writeln!(f, "Retries: {retries}")?;
+        }
+        if let Some(retry_backoff) = self.retry_backoff {
+            writeln!(f, "Retry Backoff: {retry_backoff}ms")?;
+        }
+        if let Some(verify) = self.verify {
+            writeln!(f, "Verify Outputs: {}", if verify { "Yes" } else { "No" })?;
+        }
+        if let Some(verify_tolerance) = self.verify_tolerance {
+            writeln!(f, "Verify Tolerance: {verify_tolerance}ms")?;
+        }
+        writeln!(f, "Cache Directory: {}", self.cache_dir().to_string_lossy())?;
+        if let Some(location) = self
+            .store
+            .as_ref()
+            .and_then(|store| store.location.as_ref())
+        {
+            writeln!(f, "Store: {location}")?;
+        }
+        if let Some(ref output_name) = self.output_name {
+            writeln!(f, "Output Name: {output_name}")?;
+        }
+        if let Some(ogg_quality) = self.ogg_quality {
+            writeln!(f, "Ogg Quality: {ogg_quality}")?;
+        }
         writeln!(f, "Packages:")?;
         if self.packages.is_empty() {
             writeln!(f, "  [None]")?;
@@ -154,12 +529,38 @@ impl fmt::Display for Config {
                 if let Some(ref include_flac) = package.include_flac {
                     writeln!(f, "    Include Flac: {include_flac}")?;
                 }
+                if let Some(ref formats) = package.formats {
+                    writeln!(f, "    Formats: {formats:?}")?;
+                }
                 if let Some(ref bitrate) = package.bitrate {
                     writeln!(f, "    Bitrate: {bitrate} kbps")?;
                 }
+                if let Some(ref samplerate) = package.samplerate {
+                    writeln!(f, "    Sample Rate: {samplerate} Hz")?;
+                }
+                if let Some(ref sprite) = package.sprite {
+                    writeln!(f, "    Sprite: {sprite:?}")?;
+                }
+                if let Some(recursive) = package.recursive {
+                    writeln!(f, "    Recursive: {}", if recursive { "Yes" } else { "No" })?;
+                }
+                if let Some(max_depth) = package.max_depth {
+                    writeln!(f, "    Max Depth: {max_depth}")?;
+                }
+                if let Some(ref ignore) = package.ignore {
+                    writeln!(f, "    Ignore: {ignore:?}")?;
+                }
                 if let Some(ref extends) = package.extends {
                     writeln!(f, "    Extends: {extends:?}")?;
                 }
+                if !package.inherited_sounds.is_empty() {
+                    let layers: Vec<&str> = package
+                        .inherited_sounds
+                        .iter()
+                        .map(|layer| layer.package.as_str())
+                        .collect();
+                    writeln!(f, "    Inherited Sounds: {layers:?}")?;
+                }
                 if let Some(ref languages) = package.languages {
                     writeln!(f, "    Languages: {languages:?}")?;
                 }
@@ -173,6 +574,15 @@ impl fmt::Display for Config {
                         if let Some(channels) = source.channels {
                             writeln!(f, "        Channels: {channels}")?;
                         }
+                        if let Some(ref formats) = source.formats {
+                            writeln!(f, "        Formats: {formats:?}")?;
+                        }
+                        if let Some(samplerate) = source.samplerate {
+                            writeln!(f, "        Sample Rate: {samplerate} Hz")?;
+                        }
+                        if let Some(ref downmix) = source.downmix {
+                            writeln!(f, "        Downmix: {downmix:?}")?;
+                        }
                         writeln!(f, "      }}")?;
                     }
                 }
@@ -183,6 +593,182 @@ impl fmt::Display for Config {
     }
 }
 
+impl Config {
+    /// The formats used by all packages.
+    /// An explicit `formats` list takes precedence over the `include_*` flags.
+    pub fn formats(&self) -> Vec<Format> {
+        if let Some(ref formats) = self.formats {
+            return format::dedup(formats);
+        }
+        let mut formats = Vec::new();
+        if self.include_webm.unwrap_or(true) {
+            formats.push(Format::Webm);
+        }
+        if self.include_opus.unwrap_or(false) {
+            formats.push(Format::Opus);
+        }
+        if self.include_mp4.unwrap_or(false) {
+            formats.push(Format::Mp4);
+        }
+        if self.include_flac.unwrap_or(false) {
+            formats.push(Format::Flac);
+        }
+        if self.include_ogg.unwrap_or(false) {
+            formats.push(Format::Ogg);
+        }
+        formats
+    }
+}
+
+fn extends_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Resolves `name` after the packages it extends, `stack` holds the packages being resolved
+/// to find cycles. Returns the package with the sound layers of every package below it,
+/// which it only uses itself with `inherit_sounds`, but passes on to the packages extending it.
+fn resolve_package(
+    name: &str,
+    packages: &HashMap<String, Package>,
+    resolved: &mut HashMap<String, (Package, Vec<SoundLayer>)>,
+    stack: &mut Vec<String>,
+) -> io::Result<(Package, Vec<SoundLayer>)> {
+    if let Some(package) = resolved.get(name) {
+        return Ok(package.clone());
+    }
+    if let Some(start) = stack.iter().position(|package| package == name) {
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(name.to_string());
+        return Err(extends_error(format!(
+            "Packages extend each other in a cycle: {}",
+            cycle.join(" -> ")
+        )));
+    }
+    let package = &packages[name];
+    stack.push(name.to_string());
+    let mut inherited: Option<Package> = None;
+    let mut layers: Vec<SoundLayer> = Vec::new();
+    for parent_name in package.extends.iter().flatten() {
+        let Some(parent) = packages.get(parent_name) else {
+            return Err(extends_error(format!(
+                "Package {name} extends {parent_name}, which is not in the config"
+            )));
+        };
+        let (parent_resolved, parent_layers) =
+            resolve_package(parent_name, packages, resolved, stack)?;
+        let parent_layer = SoundLayer {
+            package: parent_name.clone(),
+            sourcedir: parent.sourcedir.clone().unwrap_or("sounds".to_string()),
+            languages: parent.languages.clone(),
+        };
+        // a package reached through two parents keeps its first, lowest, place
+        for layer in parent_layers.iter().chain([&parent_layer]) {
+            if !layers.iter().any(|known| known.package == layer.package) {
+                layers.push(layer.clone());
+            }
+        }
+        inherited = Some(match inherited {
+            Some(earlier) => parent_resolved.inherit(earlier),
+            None => parent_resolved,
+        });
+    }
+    stack.pop();
+    let mut package = match inherited {
+        Some(inherited) => package.clone().inherit(inherited),
+        None => package.clone(),
+    };
+    if package.inherit_sounds.unwrap_or(false) {
+        package.inherited_sounds = layers.clone();
+    }
+    resolved.insert(name.to_string(), (package.clone(), layers.clone()));
+    Ok((package, layers))
+}
+
+/// Merges two maps, the entries of `own` replace those of `inherited` with the same key.
+fn merge_maps<T>(
+    own: Option<HashMap<String, T>>,
+    inherited: Option<HashMap<String, T>>,
+) -> Option<HashMap<String, T>> {
+    match (own, inherited) {
+        (Some(own), Some(mut inherited)) => {
+            inherited.extend(own);
+            Some(inherited)
+        }
+        (own, inherited) => own.or(inherited),
+    }
+}
+
+impl Package {
+    /// This package on top of the resolved `parent`, the settings set here win.
+    /// `sourcedir`, `extends`, the sounds and the descriptive fields are never inherited here.
+    fn inherit(self, parent: Package) -> Package {
+        Package {
+            sourcedir: self.sourcedir,
+            bitrate: self.bitrate.or(parent.bitrate),
+            extends: self.extends,
+            inherit_sounds: self.inherit_sounds,
+            languages: merge_maps(self.languages, parent.languages),
+            sources: merge_maps(self.sources, parent.sources),
+            include_flac: self.include_flac.or(parent.include_flac),
+            formats: self.formats.or(parent.formats),
+            samplerate: self.samplerate.or(parent.samplerate),
+            sprite: self.sprite.or(parent.sprite),
+            recursive: self.recursive.or(parent.recursive),
+            max_depth: self.max_depth.or(parent.max_depth),
+            ignore: self.ignore.or(parent.ignore),
+            author: self.author,
+            description: self.description,
+            tags: self.tags,
+            inherited_sounds: self.inherited_sounds,
+        }
+    }
+
+    /// The formats for this package, a `formats` list replaces the inherited one,
+    /// otherwise `include_flac` adds flac to the inherited formats.
+    pub fn formats(&self, inherited: &[Format]) -> Vec<Format> {
+        if let Some(ref formats) = self.formats {
+            return format::dedup(formats);
+        }
+        let mut formats = inherited.to_vec();
+        if self.include_flac.unwrap_or(false) {
+            formats.push(Format::Flac);
+        }
+        format::dedup(&formats)
+    }
+}
+
+impl Source {
+    /// The formats for this source, a `formats` list replaces the inherited package formats.
+    pub fn formats(&self, inherited: &[Format]) -> Vec<Format> {
+        match self.formats {
+            Some(ref formats) => format::dedup(formats),
+            None => inherited.to_vec(),
+        }
+    }
+}
+
+/// The error of serde at `path:line:column`, followed by the line with a marker below the column.
+fn parse_error(config_path: &str, contents: &str, e: &serde_json::Error) -> io::Error {
+    let message = e.to_string();
+    // the location is given in front instead
+    let message = message
+        .rsplit_once(" at line ")
+        .map_or(message.as_str(), |(message, _)| message);
+    let mut text = format!("{config_path}:{}:{}: {message}", e.line(), e.column());
+    if let Some(line) = contents.lines().nth(e.line().saturating_sub(1)) {
+        // serde counts columns in bytes, tabs are kept so the marker lines up
+        let indent: String = line
+            .char_indices()
+            .take_while(|(index, _)| *index < e.column().saturating_sub(1))
+            .map(|(_, c)| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let number = e.line().to_string();
+        let gutter = " ".repeat(number.len());
+        text.push_str(&format!("\n {number} | {line}\n {gutter} | {indent}^"));
+    }
+    io::Error::new(io::ErrorKind::InvalidData, text)
+}
+
 fn join_path(a: &str, b: &str) -> String {
     Path::new(a).join(b).to_str().unwrap_or("").to_string()
 }
@@ -238,7 +824,7 @@ pub fn strip_jsonc_comments(jsonc_input: &str, preserve_locations: bool) -> Stri
                     }
                 } else {
                     if preserve_locations {
-                        json_output.push_str(" ");
+                        json_output.push(' ');
                     }
                 }
                 last_char = Some(cur_char);
@@ -263,3 +849,132 @@ pub fn strip_jsonc_comments(jsonc_input: &str, preserve_locations: bool) -> Stri
 
     json_output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(packages: &str) -> Config {
+        let json =
+            format!(r#"{{"indir": "in", "outdir": "out", "bitrate": 96, "packages": {packages}}}"#);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn resolves_extends_in_order() {
+        let mut config = config(
+            r#"{
+                "base": {"bitrate": 48, "samplerate": 44100, "sources": {"a": {"bitrate": 32}}},
+                "loud": {"bitrate": 128},
+                "skin": {
+                    "extends": ["base", "loud"],
+                    "inherit_sounds": true,
+                    "sources": {"b": {"channels": 1}}
+                }
+            }"#,
+        );
+        config.resolve_extends().unwrap();
+        let skin = &config.packages["skin"];
+        assert_eq!(skin.bitrate, Some(128));
+        assert_eq!(skin.samplerate, Some(44100));
+        let sources = skin.sources.as_ref().unwrap();
+        assert!(sources.contains_key("a") && sources.contains_key("b"));
+        let layers: Vec<&str> = skin
+            .inherited_sounds
+            .iter()
+            .map(|layer| layer.package.as_str())
+            .collect();
+        assert_eq!(layers, ["base", "loud"]);
+        assert!(config.packages["base"].inherited_sounds.is_empty());
+    }
+
+    #[test]
+    fn rejects_cycles_and_unknown_packages() {
+        let mut cycle = config(r#"{"a": {"extends": ["b"]}, "b": {"extends": ["a"]}}"#);
+        let error = cycle.resolve_extends().unwrap_err();
+        assert!(error.to_string().contains("cycle"));
+        let mut unknown = config(r#"{"a": {"extends": ["missing"]}}"#);
+        assert!(unknown.resolve_extends().is_err());
+    }
+
+    #[test]
+    fn inherits_sounds_through_packages_that_do_not() {
+        let mut config = config(
+            r#"{
+                "base": {},
+                "mid": {"extends": ["base"]},
+                "skin": {"extends": ["mid"], "inherit_sounds": true}
+            }"#,
+        );
+        config.resolve_extends().unwrap();
+        let layers: Vec<&str> = config.packages["skin"]
+            .inherited_sounds
+            .iter()
+            .map(|layer| layer.package.as_str())
+            .collect();
+        assert_eq!(layers, ["base", "mid"]);
+        assert!(config.packages["mid"].inherited_sounds.is_empty());
+    }
+
+    #[test]
+    fn takes_the_wrapper_flags_after_a_subcommand() {
+        <Args as clap::CommandFactory>::command().debug_assert();
+        for subcommand in [
+            &["check-config"][..],
+            &["clean", "--dry-run"],
+            &["cache", "stats"],
+            &["watch", "--poll"],
+        ] {
+            let args = ["scode"]
+                .iter()
+                .chain(subcommand)
+                .chain(&["--ffmpeg=ffmpeg", "--yes=true"]);
+            let parsed = Args::try_parse_from(args).unwrap();
+            assert_eq!(parsed.ffmpeg.as_deref(), Some("ffmpeg"));
+            assert_eq!(parsed.yes, Some(true));
+        }
+    }
+
+    /// The error of loading a config file with `contents`.
+    fn load_error(name: &str, contents: &str) -> (String, String) {
+        let path = std::env::temp_dir().join(format!("scode-{name}-{}.jsonc", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let error = Config::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        (path, error.to_string())
+    }
+
+    #[test]
+    fn rejects_unknown_keys_where_they_are() {
+        let (path, error) = load_error(
+            "unknown",
+            r#"{
+    "indir": "in",
+    "outdir": "out",
+    "bitrate": 24,
+    "packages": {
+        "ui": {
+            "bitrte": 64
+        }
+    }
+}"#,
+        );
+        assert!(error.starts_with(&format!("{path}:7:20: unknown field `bitrte`")));
+        assert!(error.ends_with("\n 7 |             \"bitrte\": 64\n   |                    ^"));
+    }
+
+    #[test]
+    fn locates_errors_after_comments() {
+        let (path, error) = load_error(
+            "syntax",
+            r#"{
+    /* settings
+       for every package */ "indir": "in",
+    "outdir": "out", // relative to the config
+    "bitrate": /* kbps */ 24 "packages": {}
+}"#,
+        );
+        assert!(error.starts_with(&format!("{path}:5:31: expected `,` or `}}`")));
+    }
+}
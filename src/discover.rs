@@ -0,0 +1,230 @@
+// Finds the wav files of a package.
+// By default only the files directly in the source or language directory are used,
+// with `recursive` the subdirectories are searched too and the path relative to
+// the directory becomes part of the name, like `footsteps/grass_01`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{Config, Package};
+
+/// A wav file of a package.
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// Path relative to the source or language directory, with `/` between
+    /// directories and without the extension.
+    pub name: String,
+    /// Package the file belongs to when it is inherited through `extends`.
+    pub inherited_from: Option<String>,
+}
+
+/// Which files of the directories of a package are sounds.
+pub struct Walk {
+    /// Levels of subdirectories that are searched, 0 when not recursive.
+    max_depth: usize,
+    ignore: Vec<glob::Pattern>,
+}
+
+fn is_wav(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "wav")
+}
+
+impl Walk {
+    /// Fails with `Unsupported` when an ignore pattern is invalid.
+    pub fn from_package(package_name: &str, package: &Package) -> io::Result<Self> {
+        let max_depth = match package.recursive {
+            Some(true) => package.max_depth.unwrap_or(usize::MAX),
+            _ => 0,
+        };
+        let ignore = package
+            .ignore
+            .iter()
+            .flatten()
+            .map(|pattern| {
+                glob::Pattern::new(pattern).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("Invalid ignore pattern {pattern} in package {package_name}: {e}"),
+                    )
+                })
+            })
+            .collect::<io::Result<Vec<glob::Pattern>>>()?;
+        Ok(Walk { max_depth, ignore })
+    }
+
+    fn is_ignored(&self, relative: &str) -> bool {
+        self.ignore.iter().any(|pattern| pattern.matches(relative))
+    }
+
+    /// The name of the wav at `path` inside `dir`, `None` when it is not
+    /// a sound of the package because it is ignored or too deep.
+    pub fn name(&self, dir: &Path, path: &Path) -> Option<String> {
+        if !is_wav(path) {
+            return None;
+        }
+        let relative = path.strip_prefix(dir).ok()?;
+        let components: Vec<String> = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        if components.is_empty() || components.len() - 1 > self.max_depth {
+            return None;
+        }
+        // every directory on the way can be ignored too
+        for end in 1..=components.len() {
+            if self.is_ignored(&components[..end].join("/")) {
+                return None;
+            }
+        }
+        let relative = components.join("/");
+        Some(
+            relative
+                .strip_suffix(".wav")
+                .unwrap_or(&relative)
+                .to_string(),
+        )
+    }
+
+    /// The wavs in `dir` and the subdirectories allowed, leaving out the `skip` directories.
+    fn files(&self, dir: &Path, skip: &HashSet<PathBuf>) -> io::Result<Vec<(PathBuf, String)>> {
+        let mut files: Vec<(PathBuf, String)> = Vec::new();
+        let mut visited: HashSet<PathBuf> = HashSet::new();
+        let mut pending: Vec<(PathBuf, usize)> = vec![(dir.to_path_buf(), 0)];
+        while let Some((current, depth)) = pending.pop() {
+            // a symlink back up the tree would otherwise be followed forever
+            if !visited.insert(current.canonicalize()?) {
+                continue;
+            }
+            for entry in fs::read_dir(&current)?.filter_map(Result::ok) {
+                let path = entry.path();
+                if path.is_dir() {
+                    let nested = depth < self.max_depth
+                        && !path.canonicalize().is_ok_and(|path| skip.contains(&path));
+                    if nested {
+                        pending.push((path, depth + 1));
+                    }
+                } else if let Some(name) = self.name(dir, &path) {
+                    files.push((path, name));
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Directories of the other languages of a package that has one inside another,
+/// like `_` mapped to the sourcedir itself.
+fn language_dirs(
+    sourcedir: &Path,
+    languages: Option<&HashMap<String, String>>,
+) -> HashSet<PathBuf> {
+    languages
+        .into_iter()
+        .flatten()
+        .filter_map(|(_, dir)| sourcedir.join(dir).canonicalize().ok())
+        .collect()
+}
+
+/// Fails with `Unsupported` when two names only differ in case, their outputs
+/// would overwrite each other on file systems that ignore case.
+fn check_unique(package_name: &str, files: &[SourceFile]) -> io::Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for file in files {
+        if let Some(other) = seen.insert(file.name.to_lowercase(), &file.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Sounds {other} and {} of package {package_name} only differ in case",
+                    file.name
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The files of one language of a package, with the package each inherited file comes from.
+/// Inherited sounds are read first, so a file of the package itself, or of a package
+/// later in `extends`, replaces an inherited file with the same name.
+pub fn source_files(
+    config: &Config,
+    package_name: &str,
+    package_config: &Package,
+    lang: &str,
+    lang_dir: Option<&str>,
+) -> io::Result<Vec<SourceFile>> {
+    let walk = Walk::from_package(package_name, package_config)?;
+    let indir_path = Path::new(&config.indir);
+    let sourcedir = package_config.sourcedir.as_deref().unwrap_or("sounds");
+    let sourcedir_path = indir_path.join(package_name).join(sourcedir);
+    let own_dir = lang_dir.map_or(sourcedir_path.clone(), |dir| sourcedir_path.join(dir));
+    let mut dirs: Vec<(PathBuf, HashSet<PathBuf>, Option<String>)> = Vec::new();
+    for layer in &package_config.inherited_sounds {
+        let layer_path = indir_path.join(&layer.package).join(&layer.sourcedir);
+        // only the same language is inherited, with the directory the extended package uses
+        let dir = match (lang_dir, &layer.languages) {
+            (None, None) => Some(layer_path.clone()),
+            (Some(_), Some(languages)) => languages.get(lang).map(|dir| layer_path.join(dir)),
+            _ => None,
+        };
+        if let Some(dir) = dir.filter(|dir| dir.is_dir()) {
+            let skip = language_dirs(&layer_path, layer.languages.as_ref());
+            dirs.push((dir, skip, Some(layer.package.clone())));
+        }
+    }
+    if own_dir.is_dir() {
+        let skip = language_dirs(&sourcedir_path, package_config.languages.as_ref());
+        dirs.push((own_dir, skip, None));
+    } else if dirs.is_empty() {
+        // a package that inherits sounds only needs the directories it adds to
+        let error_message = match lang_dir {
+            Some(_) => format!("Language dir: {own_dir:?} is not a directory!"),
+            None => format!("Sourcedir: {own_dir:?} is not a directory!"),
+        };
+        return Err(io::Error::new(io::ErrorKind::NotFound, error_message));
+    }
+
+    let mut files: HashMap<String, SourceFile> = HashMap::new();
+    for (dir, mut skip, inherited_from) in dirs {
+        if let Ok(canonical) = dir.canonicalize() {
+            skip.remove(&canonical);
+        }
+        for (path, name) in walk.files(&dir, &skip)? {
+            let file = SourceFile {
+                path,
+                name: name.clone(),
+                inherited_from: inherited_from.clone(),
+            };
+            files.insert(name, file);
+        }
+    }
+    let files: Vec<SourceFile> = files.into_values().collect();
+    check_unique(package_name, &files)?;
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_nested_files_by_their_relative_path() {
+        let walk = Walk {
+            max_depth: 1,
+            ignore: vec![glob::Pattern::new("drafts").unwrap()],
+        };
+        let dir = Path::new("sounds");
+        let name = |path: &str| walk.name(dir, &dir.join(path));
+        assert_eq!(name("click.wav").as_deref(), Some("click"));
+        assert_eq!(
+            name("footsteps/grass_01.wav").as_deref(),
+            Some("footsteps/grass_01")
+        );
+        assert_eq!(name("footsteps/deep/grass_01.wav"), None);
+        assert_eq!(name("drafts/click.wav"), None);
+        assert_eq!(name("notes.txt"), None);
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::{hash_map::RandomState, HashSet},
+    fs,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{config::Config, format::Format};
+
+/// Rough memory used by a single ffmpeg process regardless of its input.
+const PROCESS_OVERHEAD: u64 = 32 * 1024 * 1024;
+
+/// Time every encoder process gets regardless of the length of its input, in seconds.
+const DEFAULT_TIMEOUT: f64 = 60.0;
+/// Extra time per second of input audio, in seconds.
+const DEFAULT_TIMEOUT_FACTOR: f64 = 10.0;
+const DEFAULT_RETRIES: u32 = 2;
+/// Wait before the first retry in milliseconds, doubled for every following retry.
+const DEFAULT_RETRY_BACKOFF: u64 = 500;
+/// How often a running process is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Set when the user asked to stop, no new processes are started once it is set
+/// and the running ones are killed.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// Pids of the running encoder processes, killed when the user quits immediately.
+/// Each leads its own process group, so the Ctrl-C of the terminal does not reach them.
+static RUNNING: Mutex<Option<HashSet<u32>>> = Mutex::new(None);
+
+/// Appended to the name of a file while it is being written.
+const TEMP_SUFFIX: &str = ".scode.tmp";
+
+/// Settings shared by every encoder process.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub ffmpeg: String,
+    pub ffprobe: String,
+    pub ogg_quality: Option<f32>,
+    pub threads: Option<u32>,
+    pub timeout: f64,
+    pub timeout_factor: f64,
+    pub retries: u32,
+    pub retry_backoff: u64,
+}
+
+impl EncodeOptions {
+    pub fn from_config(config: &Config) -> Self {
+        let ffmpeg = config.ffmpeg.clone().unwrap_or("ffmpeg".to_string());
+        EncodeOptions {
+            ffprobe: config
+                .ffprobe
+                .clone()
+                .unwrap_or_else(|| ffprobe_next_to(&ffmpeg)),
+            ffmpeg,
+            ogg_quality: config.ogg_quality,
+            threads: config.threads,
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            timeout_factor: config.timeout_factor.unwrap_or(DEFAULT_TIMEOUT_FACTOR),
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            retry_backoff: config.retry_backoff.unwrap_or(DEFAULT_RETRY_BACKOFF),
+        }
+    }
+
+    /// Time a process handling `duration` seconds of audio may run before it is killed.
+    pub fn timeout_for(&self, duration: f64) -> Duration {
+        Duration::from_secs_f64((self.timeout + self.timeout_factor * duration.max(0.0)).max(0.0))
+    }
+
+    /// Runs an ffmpeg command for `duration` seconds of audio.
+    /// Runs that could not be started or were killed by a signal are retried with a growing
+    /// backoff. A normal non-zero exit is not retried, bad arguments or a corrupt input fail
+    /// the same way every time. A run that does not finish in time is killed and returned
+    /// as an error of kind `TimedOut` without retrying, since a hung input will hang again.
+    pub fn run(&self, command: &mut Command, duration: f64) -> io::Result<Output> {
+        let timeout = self.timeout_for(duration);
+        let mut attempt = 0;
+        loop {
+            let result = run_with_timeout(command, timeout);
+            let retry = match &result {
+                // no exit code means the process was terminated by a signal
+                Ok(output) => output.status.code().is_none(),
+                Err(e) => !matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::NotFound | io::ErrorKind::Interrupted
+                ),
+            };
+            if !retry || attempt >= self.retries || is_cancelled() {
+                return result;
+            }
+            let backoff = self.retry_backoff.saturating_mul(1 << attempt.min(16));
+            attempt += 1;
+            debug!(
+                "Retrying {:?} in {backoff}ms, attempt {attempt} of {}",
+                command.get_program(),
+                self.retries
+            );
+            thread::sleep(Duration::from_millis(backoff));
+        }
+    }
+
+    /// Runs an ffmpeg command that writes `format` to `path`.
+    /// The output is written to a temporary file next to `path` and only renamed
+    /// into place when ffmpeg succeeds, so `path` never holds a partial file.
+    pub fn run_to_file(
+        &self,
+        command: &mut Command,
+        format: Format,
+        path: &Path,
+        duration: f64,
+    ) -> io::Result<Output> {
+        // output names may put files in subdirectories of outdir
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = temp_path(path);
+        // the muxer can not be guessed from the temporary extension
+        command.arg("-f").arg(format.muxer()).arg(&temp);
+        let result = self.run(command, duration);
+        match result {
+            Ok(ref output) if output.status.success() => {
+                if let Err(e) = fs::rename(&temp, path) {
+                    let _ = fs::remove_file(&temp);
+                    return Err(e);
+                }
+            }
+            _ => {
+                let _ = fs::remove_file(&temp);
+            }
+        }
+        result
+    }
+
+    /// Arguments limiting the threads of the encoder, placed before the codec arguments.
+    pub fn thread_args(&self) -> Vec<String> {
+        match self.threads {
+            Some(threads) => vec!["-threads".to_string(), threads.to_string()],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// The ffprobe that comes with the given ffmpeg, `ffmpeg.exe` becomes `ffprobe.exe`.
+fn ffprobe_next_to(ffmpeg: &str) -> String {
+    let path = Path::new(ffmpeg);
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.starts_with("ffmpeg") => path
+            .with_file_name(name.replacen("ffmpeg", "ffprobe", 1))
+            .to_string_lossy()
+            .into_owned(),
+        _ => "ffprobe".to_string(),
+    }
+}
+
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+fn cancelled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Cancelled")
+}
+
+/// Stops new jobs on the first Ctrl-C and exits right away on the second,
+/// killing the encoder processes that are still running.
+pub fn handle_ctrlc() -> io::Result<()> {
+    ctrlc::set_handler(|| {
+        if is_cancelled() {
+            kill_running();
+            std::process::exit(130);
+        }
+        warn!("Stopping, press Ctrl-C again to quit immediately");
+        cancel();
+    })
+    .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Returns an `Interrupted` error once the user asked to stop,
+/// for jobs to check before they start.
+pub fn check_cancelled() -> io::Result<()> {
+    if is_cancelled() {
+        return Err(cancelled_error());
+    }
+    Ok(())
+}
+
+/// Runs `f` on a thread pool with `jobs` threads,
+/// or on the global pool with one thread per core when `jobs` is not set.
+pub fn with_jobs<T, F>(jobs: Option<usize>, f: F) -> io::Result<T>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    match jobs {
+        Some(jobs) if jobs > 0 => {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(io::Error::other)?;
+            Ok(pool.install(f))
+        }
+        _ => Ok(f()),
+    }
+}
+
+/// Spawns the command and waits for it at most `timeout`,
+/// killing it together with every process it started when the time runs out
+/// or when the run is cancelled.
+pub fn run_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    check_cancelled()?;
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // own process group, so that the whole tree can be killed at once,
+        // this also keeps the Ctrl-C of the terminal from reaching it before scode can clean up
+        command.process_group(0);
+    }
+    let start = Instant::now();
+    let mut child = command.spawn()?;
+    let _running = Running::register(child.id());
+    // drain the pipes while waiting, a full pipe would block the child forever
+    let stdout = child.stdout.take().map(drain);
+    let stderr = child.stderr.take().map(drain);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if is_cancelled() {
+            kill_tree(&mut child);
+            let _ = child.wait();
+            return Err(cancelled_error());
+        }
+        if start.elapsed() >= timeout {
+            kill_tree(&mut child);
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{:?} did not finish within {:.0}s and was killed",
+                    command.get_program(),
+                    timeout.as_secs_f64()
+                ),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let join = |handle: Option<thread::JoinHandle<Vec<u8>>>| {
+        handle
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default()
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn drain<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = reader.read_to_end(&mut buffer);
+        buffer
+    })
+}
+
+/// Keeps the pid of a running process in `RUNNING` until it is dropped.
+struct Running(u32);
+
+impl Running {
+    fn register(pid: u32) -> Self {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        running.get_or_insert_with(HashSet::new).insert(pid);
+        Running(pid)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(running) = running.as_mut() {
+            running.remove(&self.0);
+        }
+    }
+}
+
+/// Kills every running encoder process and everything it spawned.
+fn kill_running() {
+    let running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    for &pid in running.iter().flatten() {
+        kill_group(pid);
+    }
+}
+
+/// Kills a process that leads its own group, and everything it spawned.
+#[cfg(unix)]
+fn kill_group(pid: u32) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        // a negative pid signals the whole group
+        unsafe {
+            libc::kill(-pid, libc::SIGKILL);
+        }
+    }
+}
+
+/// Kills a process that leads its own group, and everything it spawned.
+#[cfg(windows)]
+fn kill_group(pid: u32) {
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .output();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_group(_pid: u32) {}
+
+/// Kills a child and everything it spawned.
+fn kill_tree(child: &mut Child) {
+    kill_group(child.id());
+    let _ = child.kill();
+}
+
+/// Name a file is written under until it is complete.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Name a file is written under until it is complete, unique to this write, for directories
+/// that other processes or machines write the same files to.
+pub fn unique_temp_path(path: &Path) -> PathBuf {
+    // every RandomState is seeded differently
+    let random = RandomState::new().build_hasher().finish();
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}.{random:016x}{TEMP_SUFFIX}",
+        std::process::id()
+    ));
+    path.with_file_name(name)
+}
+
+/// Whether `path` is a file that is still being written, or was left over by an interrupted run.
+pub fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(TEMP_SUFFIX))
+}
+
+/// Removes the temporary files that interrupted runs left in `dir` and its subdirectories.
+/// Returns the number of files removed.
+pub fn remove_temp_files(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            removed += remove_temp_files(&path)?;
+        } else if is_temp_file(&path) {
+            debug!("Removing leftover {}", path.to_string_lossy());
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Size of the file at `path` in bytes, 0 when it can not be read.
+pub fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+/// Estimated peak memory of encoding sources of `size` bytes in total.
+pub fn estimate_memory_for_size(size: u64) -> u64 {
+    PROCESS_OVERHEAD + size * 2
+}
+
+/// Estimated peak memory of encoding the file at `path`.
+pub fn estimate_memory(path: &Path) -> u64 {
+    estimate_memory_for_size(file_size(path))
+}
+
+/// Blocks new jobs while the estimated memory of the running jobs is above a budget.
+pub struct MemoryLimiter {
+    budget: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+pub struct MemoryPermit<'a> {
+    limiter: &'a MemoryLimiter,
+    amount: u64,
+}
+
+impl MemoryLimiter {
+    pub fn new(budget_mb: u64) -> Self {
+        MemoryLimiter {
+            budget: budget_mb * 1024 * 1024,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until `amount` fits in the budget.
+    /// A job larger than the whole budget is let through once nothing else is running.
+    pub fn acquire(&self, amount: u64) -> MemoryPermit<'_> {
+        let mut used = self.used.lock().unwrap();
+        while *used > 0 && *used + amount > self.budget {
+            used = self.released.wait(used).unwrap();
+        }
+        *used += amount;
+        MemoryPermit {
+            limiter: self,
+            amount,
+        }
+    }
+}
+
+impl Drop for MemoryPermit<'_> {
+    fn drop(&mut self) {
+        let mut used = self.limiter.used.lock().unwrap();
+        *used = used.saturating_sub(self.amount);
+        self.limiter.released.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_only_temp_files() {
+        let dir = std::env::temp_dir().join(format!("scode-temp-{}", std::process::id()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let output = dir.join("96kb.1ch.48000hz.0123456789.webm");
+        fs::write(&output, b"done").unwrap();
+        fs::write(temp_path(&output), b"partial").unwrap();
+        fs::write(temp_path(&dir.join("nested").join("a.mp4")), b"partial").unwrap();
+        assert_eq!(remove_temp_files(&dir).unwrap(), 2);
+        assert!(output.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kills_a_hung_process_tree() {
+        let start = Instant::now();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 30 & wait");
+        let error = run_with_timeout(&mut command, Duration::from_millis(200)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        // the pipes close only once the backgrounded sleep is gone as well
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn does_not_retry_a_failed_exit() {
+        let options = EncodeOptions {
+            ffmpeg: "ffmpeg".to_string(),
+            ffprobe: "ffprobe".to_string(),
+            ogg_quality: None,
+            threads: None,
+            timeout: DEFAULT_TIMEOUT,
+            timeout_factor: DEFAULT_TIMEOUT_FACTOR,
+            retries: 3,
+            retry_backoff: 10_000,
+        };
+        let start = Instant::now();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("exit 1");
+        let output = options.run(&mut command, 0.0).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// An output format that scode can encode to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Webm,
+    Opus,
+    Mp4,
+    Flac,
+    Ogg,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Webm => "webm",
+            Format::Opus => "opus",
+            Format::Mp4 => "mp4",
+            Format::Flac => "flac",
+            Format::Ogg => "ogg",
+        }
+    }
+
+    /// Name of the ffmpeg muxer, needed when the output name does not end in the extension.
+    /// Every format is muxed by the muxer named after its extension.
+    pub fn muxer(self) -> &'static str {
+        self.extension()
+    }
+
+    /// Name ffprobe reports for the codec of this format.
+    pub fn codec_name(self) -> &'static str {
+        match self {
+            Format::Webm | Format::Opus => "opus",
+            Format::Mp4 => "aac",
+            Format::Flac => "flac",
+            Format::Ogg => "vorbis",
+        }
+    }
+
+    /// Whether the encoder for this format can output the given sample rate.
+    /// Opus and aac only operate at a fixed set of rates.
+    pub fn supports_sample_rate(self, sample_rate: u32) -> bool {
+        match self {
+            Format::Webm | Format::Opus => {
+                matches!(sample_rate, 8000 | 12000 | 16000 | 24000 | 48000)
+            }
+            Format::Mp4 => matches!(
+                sample_rate,
+                8000 | 11025
+                    | 12000
+                    | 16000
+                    | 22050
+                    | 24000
+                    | 32000
+                    | 44100
+                    | 48000
+                    | 64000
+                    | 88200
+                    | 96000
+            ),
+            Format::Flac | Format::Ogg => (8000..=192_000).contains(&sample_rate),
+        }
+    }
+
+    /// Rough size in bytes of `num_samples` samples encoded to this format.
+    /// `bitrate` is per channel in kbps like everywhere else, flac is assumed
+    /// to compress 16 bit audio to about 60%.
+    pub fn estimated_size(
+        self,
+        bitrate: u32,
+        channels: u16,
+        sample_rate: u32,
+        num_samples: usize,
+    ) -> u64 {
+        let duration = num_samples as f64 / f64::from(sample_rate.max(1));
+        let bytes_per_second = match self {
+            Format::Flac => f64::from(sample_rate) * f64::from(channels) * 2.0 * 0.6,
+            _ => f64::from(bitrate) * f64::from(channels) * 1000.0 / 8.0,
+        };
+        (duration * bytes_per_second) as u64
+    }
+
+    /// The ffmpeg codec arguments for encoding to this format.
+    pub fn codec_args(self, target_channels: u16, ogg_quality: Option<f32>) -> Vec<String> {
+        let mut args: Vec<String> = Vec::new();
+        match self {
+            Format::Webm | Format::Opus => {
+                args.extend(["-c:a".to_string(), "libopus".to_string()]);
+                if target_channels > 2 {
+                    // surround layouts need the vorbis channel mapping
+                    args.extend(["-mapping_family".to_string(), "1".to_string()]);
+                }
+            }
+            Format::Mp4 => {
+                args.extend(["-c:a", "aac", "-movflags", "+faststart"].map(String::from));
+            }
+            Format::Flac => {
+                args.extend(["-c:a".to_string(), "flac".to_string()]);
+            }
+            Format::Ogg => {
+                // a vorbis quality setting takes precedence over the bitrate
+                args.extend(["-c:a".to_string(), "libvorbis".to_string()]);
+                if let Some(quality) = ogg_quality {
+                    args.extend(["-q:a".to_string(), quality.to_string()]);
+                }
+            }
+        }
+        args
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// Removes duplicate formats while keeping the order they were listed in.
+pub fn dedup(formats: &[Format]) -> Vec<Format> {
+    let mut result: Vec<Format> = Vec::with_capacity(formats.len());
+    for format in formats {
+        if !result.contains(format) {
+            result.push(*format);
+        }
+    }
+    result
+}
@@ -1,30 +1,127 @@
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
     collections::HashMap,
     fs::{self, File},
     io::{self, BufWriter, Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
-use crate::wave;
+use crate::{format::Format, probe::Delay, sprite::Sprite, wave};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Item {
     pub path: String,
     pub name: String,
+    /// Path of the outputs relative to outdir, without the extension.
     pub outfile: String,
+    /// sha256 of the source file
+    pub hash: String,
     pub package: String,
     pub lang: String,
+    /// Absolute path of the outputs, without the extension.
     pub output_path: String,
     pub bitrate: u32,
     pub num_samples: usize,
     pub input_channels: u16,
     pub target_channels: u16,
+    pub downmix: Option<Vec<Vec<f32>>>,
     pub sample_rate: u32,
+    pub target_sample_rate: u32,
     pub modification_date: String,
-    pub include_flac: bool
+    pub formats: Vec<Format>,
+    /// Fingerprint of the settings the outputs are encoded with.
+    pub fingerprint: String,
+    pub delays: HashMap<Format, Delay>,
+    /// Package the source belongs to when it is inherited through `extends`.
+    pub inherited_from: Option<String>,
+    /// Whether outputs that already exist were encoded from another source or
+    /// with other settings, and have to be encoded again.
+    #[serde(skip)]
+    pub outdated: bool,
 }
 
+impl Item {
+    /// Path of the encoded output for the given format.
+    pub fn output_path_for(&self, format: Format) -> PathBuf {
+        // the stem may contain dots, so the extension is appended instead of replaced
+        PathBuf::from(format!("{}.{}", self.output_path, format.extension()))
+    }
+}
+
+/// `Item` as cached by version 1, before sources could be inherited.
+#[derive(Deserialize)]
+struct ItemV1 {
+    path: String,
+    name: String,
+    outfile: String,
+    hash: String,
+    package: String,
+    lang: String,
+    output_path: String,
+    bitrate: u32,
+    num_samples: usize,
+    input_channels: u16,
+    target_channels: u16,
+    downmix: Option<Vec<Vec<f32>>>,
+    sample_rate: u32,
+    target_sample_rate: u32,
+    modification_date: String,
+    formats: Vec<Format>,
+    fingerprint: String,
+    delays: HashMap<Format, Delay>,
+}
+
+impl From<ItemV1> for Item {
+    fn from(item: ItemV1) -> Self {
+        Item {
+            path: item.path,
+            name: item.name,
+            outfile: item.outfile,
+            hash: item.hash,
+            package: item.package,
+            lang: item.lang,
+            output_path: item.output_path,
+            bitrate: item.bitrate,
+            num_samples: item.num_samples,
+            input_channels: item.input_channels,
+            target_channels: item.target_channels,
+            downmix: item.downmix,
+            sample_rate: item.sample_rate,
+            target_sample_rate: item.target_sample_rate,
+            modification_date: item.modification_date,
+            formats: item.formats,
+            fingerprint: item.fingerprint,
+            delays: item.delays,
+            inherited_from: None,
+            outdated: false,
+        }
+    }
+}
+
+/// Everything in the config that changes the outputs of a source,
+/// resolved from the source, package and global settings.
+#[derive(Serialize)]
+pub struct Settings<'a> {
+    pub bitrate: u32,
+    pub channels: Option<u16>,
+    pub downmix: Option<&'a Vec<Vec<f32>>>,
+    pub formats: &'a [Format],
+    pub sample_rate: u32,
+    pub ogg_quality: Option<f32>,
+    pub output_name: Option<&'a str>,
+}
+
+impl Settings<'_> {
+    /// sha256 of the settings, equal settings always give the same fingerprint.
+    pub fn fingerprint(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        format!("{:x}", Sha256::digest(json))
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NewItem {
     pub path: String,
@@ -38,6 +135,44 @@ pub struct NewItem {
     pub wave_data: wave::Data,
 }
 
+pub const CACHE_FILE: &str = "info.bin";
+/// Readable copy of the cache, written at the debug log level.
+pub const CACHE_JSON_FILE: &str = "info.json";
+/// Start of every cache file.
+const CACHE_MAGIC: &[u8; 8] = b"SCODEBIN";
+/// Version of the cached `Item` layout, bump it whenever `Item` changes
+/// and migrate the previous version in `decode_cache` where possible.
+const CACHE_VERSION: u32 = 2;
+/// Magic, version, payload length and sha256 of the payload.
+const HEADER_LEN: usize = 8 + 4 + 8 + 32;
+
+fn invalid_cache(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Decodes the cached items written with schema `version`.
+fn decode_cache(version: u32, payload: &[u8]) -> io::Result<HashMap<String, Item>> {
+    match version {
+        CACHE_VERSION => bincode::deserialize(payload)
+            .map_err(|e| invalid_cache(format!("it could not be decoded: {e}"))),
+        // older versions are decoded into their own layout and converted to `Item` here
+        1 => bincode::deserialize::<HashMap<String, ItemV1>>(payload)
+            .map(|items| {
+                items
+                    .into_iter()
+                    .map(|(key, item)| (key, Item::from(item)))
+                    .collect()
+            })
+            .map_err(|e| invalid_cache(format!("version 1 could not be decoded: {e}"))),
+        version if version > CACHE_VERSION => Err(invalid_cache(format!(
+            "it was written by a newer scode, with version {version}"
+        ))),
+        version => Err(invalid_cache(format!(
+            "version {version} can not be migrated to version {CACHE_VERSION}"
+        ))),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Map {
     pub value: HashMap<String, Item>,
@@ -51,25 +186,76 @@ impl Default for Map {
 
 impl Map {
     pub fn new() -> Self {
-        #![allow(unused_must_use)] // if it already exists, it's fine
-        fs::create_dir_all(".cache");
         Map {
             value: HashMap::new(),
         }
     }
 
+    /// Sources are keyed by their absolute path, so the cache is found
+    /// no matter which directory scode runs from.
+    fn key(path: &str) -> String {
+        let path = Path::new(path);
+        path.canonicalize()
+            .or_else(|_| std::path::absolute(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// A source a package inherits gets its own entry, next to the one of the package
+    /// it belongs to, since both are encoded with their own settings.
+    fn inherited_key(path: &str, package: &str) -> String {
+        format!("{}#{package}", Map::key(path))
+    }
+
+    fn item_key(item: &Item) -> String {
+        match item.inherited_from {
+            Some(_) => Map::inherited_key(&item.path, &item.package),
+            None => Map::key(&item.path),
+        }
+    }
+
+    /// Path of the source of the entry at `key`.
+    pub fn source_of<'a>(key: &'a str, item: &Item) -> &'a str {
+        match item.inherited_from {
+            Some(_) => key
+                .strip_suffix(&format!("#{}", item.package))
+                .unwrap_or(key),
+            None => key,
+        }
+    }
+
     // Method to insert a new SoundFileInfo into the map
     pub fn set(&mut self, key: String, info: Item) {
-        self.value.insert(key, info);
+        self.value.insert(Map::key(&key), info);
     }
 
     pub fn get(&self, key: &str) -> Option<&Item> {
-        self.value.get(key)
+        self.value.get(&Map::key(key))
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Item> {
+        self.value.remove(&Map::key(key))
+    }
+
+    /// The entry of `path` inherited by `package`.
+    pub fn get_inherited(&self, path: &str, package: &str) -> Option<&Item> {
+        self.value.get(&Map::inherited_key(path, package))
+    }
+
+    /// The entry for the same source and package as `item`.
+    pub fn get_item(&self, item: &Item) -> Option<&Item> {
+        self.value.get(&Map::item_key(item))
+    }
+
+    /// Adds or replaces the entry for the source and package of `item`.
+    pub fn insert(&mut self, item: Item) {
+        self.value.insert(Map::item_key(&item), item);
     }
 
     pub fn from_vec(vec: Vec<Item>) -> Self {
         vec.into_iter().fold(Map::new(), |mut map, info| {
-            map.set(info.path.clone(), info);
+            map.insert(info);
             map
         })
     }
@@ -78,36 +264,78 @@ impl Map {
         Map { value: map }
     }
 
-    pub fn from_cache_bin() -> io::Result<Self> {
-        let mut file = File::open(".cache/info.bin")?;
+    /// Reads the cache, failing with `InvalidData` and the reason when it can not be used.
+    pub fn from_cache_bin(dir: &Path) -> io::Result<Self> {
+        let mut file = File::open(dir.join(CACHE_FILE))?;
         let mut encoded = Vec::new();
         file.read_to_end(&mut encoded)?;
-        let value: HashMap<String, Item> = bincode::deserialize(&encoded)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-        Ok(Map::from_map(value))
+        Map::from_cache_bytes(&encoded)
+    }
+
+    fn from_cache_bytes(encoded: &[u8]) -> io::Result<Self> {
+        if !encoded.starts_with(CACHE_MAGIC) {
+            return Err(invalid_cache(
+                "it has no header, it was written by an older scode".to_string(),
+            ));
+        }
+        if encoded.len() < HEADER_LEN {
+            return Err(invalid_cache("its header is truncated".to_string()));
+        }
+        let version = LittleEndian::read_u32(&encoded[8..12]);
+        let length = LittleEndian::read_u64(&encoded[12..20]);
+        let checksum = &encoded[20..HEADER_LEN];
+        let payload = &encoded[HEADER_LEN..];
+        if payload.len() as u64 != length {
+            return Err(invalid_cache(format!(
+                "it has {} bytes instead of {length}, it was probably truncated",
+                payload.len()
+            )));
+        }
+        if Sha256::digest(payload)[..] != *checksum {
+            return Err(invalid_cache("its checksum does not match".to_string()));
+        }
+        decode_cache(version, payload).map(Map::from_map)
     }
 
-    pub fn save_cache_bin(&self) -> io::Result<&Self> {
-        let encoded: Vec<u8> = bincode::serialize(&self.value)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-        let mut file = File::create(".cache/info.bin")?;
+    fn to_cache_bytes(&self) -> io::Result<Vec<u8>> {
+        let payload: Vec<u8> =
+            bincode::serialize(&self.value).map_err(|e| io::Error::other(e.to_string()))?;
+        let mut encoded = Vec::with_capacity(HEADER_LEN + payload.len());
+        encoded.extend_from_slice(CACHE_MAGIC);
+        encoded.write_u32::<LittleEndian>(CACHE_VERSION)?;
+        encoded.write_u64::<LittleEndian>(payload.len() as u64)?;
+        encoded.extend_from_slice(&Sha256::digest(&payload));
+        encoded.extend_from_slice(&payload);
+        Ok(encoded)
+    }
+
+    /// Writes the cache to a temporary file first and renames it into place,
+    /// so an interrupted write never replaces a good cache.
+    pub fn save_cache_bin(&self, dir: &Path) -> io::Result<&Self> {
+        let encoded = self.to_cache_bytes()?;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(CACHE_FILE);
+        let temp = crate::encoder::temp_path(&path);
+        let mut file = File::create(&temp)?;
         file.write_all(&encoded)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&temp, &path)?;
         Ok(self)
     }
 
     // pub fn from_cache_json() -> io::Result<Self> {
     //     let file = File::open(".cache/info.json")?;
     //     let value: HashMap<String, Item> = serde_json::from_reader(file)
-    //         .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    //         .map_err(|e| io::Error::other(e.to_string()))?;
     //     Ok(Map::from_map(value))
     // }
 
-    pub fn save_cache_json(&self) -> io::Result<&Self> {
-        let dir = Path::new(".cache");
-        std::fs::create_dir_all(dir)?;
-        let file = File::create(dir.join("info.json"))?;
+    pub fn save_cache_json(&self, dir: &Path) -> io::Result<&Self> {
+        fs::create_dir_all(dir)?;
+        let file = File::create(dir.join(CACHE_JSON_FILE))?;
         serde_json::to_writer_pretty(file, &self.value)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            .map_err(|e| io::Error::other(e.to_string()))?;
         Ok(self)
     }
 }
@@ -116,8 +344,11 @@ impl Map {
 pub struct AtlasItem {
     name: String,
     file: String,
-    nums: usize,  // num samples
-    lang: String, // language
+    nums: usize,                // num samples
+    lang: String,               // language
+    formats: Vec<Format>,       // available file extensions
+    delays: Vec<Option<Delay>>, // encoder priming and padding per format
+    offset: Option<usize>,      // start in samples when part of a sprite
 }
 
 impl AtlasItem {
@@ -127,15 +358,52 @@ impl AtlasItem {
             file: info.outfile.clone(),
             nums: info.num_samples,
             lang: info.lang.clone(),
+            formats: info.formats.clone(),
+            delays: info
+                .formats
+                .iter()
+                .map(|format| info.delays.get(format).copied())
+                .collect(),
+            offset: None,
+        }
+    }
+    pub fn from_sprite(info: &Item, sprite: &Sprite, offset: usize) -> Self {
+        AtlasItem {
+            name: info.name.clone(),
+            file: sprite.outfile.clone(),
+            nums: info.num_samples,
+            lang: info.lang.clone(),
+            formats: sprite.formats.clone(),
+            delays: sprite
+                .formats
+                .iter()
+                .map(|format| sprite.delays.get(format).copied())
+                .collect(),
+            offset: Some(offset),
         }
     }
     fn format(&self) -> String {
+        let formats = self
+            .formats
+            .iter()
+            .map(|ext| format!("\"{ext}\""))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let delays = self
+            .delays
+            .iter()
+            .map(|delay| match delay {
+                Some(delay) => format!("[{}, {}]", delay.priming, delay.padding),
+                None => "null".to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(", ");
+        let offset = self
+            .offset
+            .map_or_else(String::new, |offset| format!(", {offset}"));
         format!(
-            "\n  [\"{}\", \"{}\", {}, \"{}\"]",
-            self.name,
-            self.file.replace(".webm", ""),
-            self.nums,
-            self.lang,
+            "\n  [\"{}\", \"{}\", {}, \"{}\", [{}], [{}]{}]",
+            self.name, self.file, self.nums, self.lang, formats, delays, offset,
         )
     }
 }
@@ -155,9 +423,25 @@ impl AtlasMap {
         self.value.entry(key).or_default().push(info);
     }
 
-    pub fn from_vec(vec: &[Item]) -> Self {
+    pub fn from_vec(vec: &[Item], sprites: &[Sprite]) -> Self {
+        // an inherited source is a member of the sprites of some packages only
+        let members: HashMap<(&str, &str), (&Sprite, usize)> = sprites
+            .iter()
+            .flat_map(|sprite| {
+                sprite.members.iter().map(move |member| {
+                    (
+                        (sprite.package.as_str(), member.path.as_str()),
+                        (sprite, member.offset),
+                    )
+                })
+            })
+            .collect();
         vec.iter().fold(AtlasMap::new(), |mut map, info| {
-            map.set(info.package.clone(), AtlasItem::from(info));
+            let atlas_item = match members.get(&(info.package.as_str(), info.path.as_str())) {
+                Some((sprite, offset)) => AtlasItem::from_sprite(info, sprite, *offset),
+                None => AtlasItem::from(info),
+            };
+            map.set(info.package.clone(), atlas_item);
             map
         })
     }
@@ -169,16 +453,20 @@ impl AtlasMap {
     //     }
     //     let file = File::create(dirp.join(".atlas.json"))?;
     //     serde_json::to_writer_pretty(file, &self.value)
-    //         .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    //         .map_err(|e| io::Error::other(e.to_string()))?;
     //     Ok(self)
     // }
 
+    /// Writes the atlas to a temporary file first and renames it into place,
+    /// so a running app never reads a half written atlas.
     pub fn save_json_v2(&self, dir: &str) -> io::Result<&Self> {
         let dirp = Path::new(dir);
         if !dirp.exists() {
             fs::create_dir_all(dirp)?;
         }
-        let file = File::create(dirp.join(".atlas.json"))?;
+        let path = dirp.join(".atlas.json");
+        let temp = crate::encoder::temp_path(&path);
+        let file = File::create(&temp)?;
         let mut writer = BufWriter::new(file);
         writeln!(writer, "{{")?;
         for (index, package) in self.value.iter().enumerate() {
@@ -204,6 +492,9 @@ impl AtlasMap {
             )?;
         }
         writeln!(writer, "}}")?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&temp, &path)?;
         Ok(self)
     }
 }
@@ -330,3 +621,107 @@ impl AtlasMap {
 //         assert_eq!(file, expected);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> Item {
+        Item {
+            path: "sounds/click.wav".to_string(),
+            name: "click".to_string(),
+            outfile: "96kb.1ch.48000hz.0123456789".to_string(),
+            hash: "0123456789abcdef".to_string(),
+            package: "ui".to_string(),
+            lang: "_".to_string(),
+            output_path: "/encoded/96kb.1ch.48000hz.0123456789".to_string(),
+            bitrate: 96,
+            num_samples: 48000,
+            input_channels: 1,
+            target_channels: 1,
+            downmix: None,
+            sample_rate: 48000,
+            target_sample_rate: 48000,
+            modification_date: "2024-01-01T00:00:00+00:00".to_string(),
+            formats: vec![Format::Webm],
+            fingerprint: "settings".to_string(),
+            delays: HashMap::new(),
+            inherited_from: None,
+            outdated: false,
+        }
+    }
+
+    fn encoded() -> Vec<u8> {
+        let mut map = Map::new();
+        map.value.insert("click".to_string(), item());
+        map.to_cache_bytes().unwrap()
+    }
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let map = Map::from_cache_bytes(&encoded()).unwrap();
+        assert_eq!(map.value["click"].fingerprint, "settings");
+    }
+
+    #[test]
+    fn rejects_damaged_caches() {
+        let encoded = encoded();
+        let truncated = Map::from_cache_bytes(&encoded[..encoded.len() - 1]).unwrap_err();
+        assert!(truncated.to_string().contains("truncated"));
+
+        let mut corrupt = encoded.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        let corrupt = Map::from_cache_bytes(&corrupt).unwrap_err();
+        assert!(corrupt.to_string().contains("checksum"));
+
+        let legacy = bincode::serialize(&HashMap::from([("click".to_string(), item())])).unwrap();
+        assert!(Map::from_cache_bytes(&legacy).is_err());
+
+        let mut newer = encoded;
+        newer[8..12].copy_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+        let newer = Map::from_cache_bytes(&newer).unwrap_err();
+        assert!(newer.to_string().contains("newer"));
+    }
+
+    #[test]
+    fn places_inherited_sources_in_the_sprites_of_their_own_package() {
+        let base = item();
+        // the inheriting package encodes the sound at another bitrate, on its own
+        let skin = Item {
+            package: "skin".to_string(),
+            bitrate: 64,
+            outfile: "64kb.1ch.48000hz.0123456789".to_string(),
+            inherited_from: Some("ui".to_string()),
+            ..item()
+        };
+        let sprite = Sprite {
+            package: "ui".to_string(),
+            lang: "_".to_string(),
+            outfile: "_sprite.96kb".to_string(),
+            output_path: "/encoded/_sprite.96kb".to_string(),
+            hash: "fedcba9876543210".to_string(),
+            bitrate: 96,
+            target_channels: 1,
+            target_sample_rate: 48000,
+            formats: vec![Format::Webm],
+            num_samples: 96000,
+            members: vec![crate::sprite::Member {
+                path: base.path.clone(),
+                offset: 2400,
+                length: 48000,
+                downmix: None,
+            }],
+            delays: HashMap::new(),
+        };
+
+        let atlas = AtlasMap::from_vec(&[base, skin], &[sprite]);
+        let ui = &atlas.value["ui"][0];
+        assert_eq!((ui.file.as_str(), ui.offset), ("_sprite.96kb", Some(2400)));
+        let skin = &atlas.value["skin"][0];
+        assert_eq!(
+            (skin.file.as_str(), skin.offset),
+            ("64kb.1ch.48000hz.0123456789", None)
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Keeps two scode runs from writing the same cache, outputs and atlas at once.
+// The lock is an advisory lock on a file in the cache directory, which also holds
+// the pid of the run that has it, so a waiting run can say who it waits for.
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use crate::encoder;
+
+const LOCK_FILE: &str = "scode.lock";
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Held for as long as the run writes anything, released when dropped.
+pub struct RunLock {
+    file: File,
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks whether the process exists
+    let result = unsafe { libc::kill(pid, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+fn busy(pid: Option<u32>) -> io::Error {
+    let message = match pid {
+        Some(pid) => format!("another scode run is in progress (pid {pid})"),
+        None => "another scode run is in progress".to_string(),
+    };
+    io::Error::new(io::ErrorKind::ResourceBusy, message)
+}
+
+impl RunLock {
+    /// Takes the lock in `dir`. When another run has it, fails with `ResourceBusy`,
+    /// or with `wait` keeps trying until that run is done or the user cancels.
+    pub fn acquire(dir: &Path, wait: bool) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut waiting = false;
+        loop {
+            let pid = read_pid(&mut file);
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {}
+                // file systems without locks, like some network mounts, only have the pid to go by
+                Err(TryLockError::Error(e)) => {
+                    debug!("Could not lock {}: {e}", path.to_string_lossy());
+                    match pid {
+                        Some(pid) if pid != std::process::id() && is_running(pid) => {}
+                        _ => break,
+                    }
+                }
+            }
+            if !wait {
+                return Err(busy(pid));
+            }
+            if !waiting {
+                info!("{}, waiting for it to finish", busy(pid));
+                waiting = true;
+            }
+            encoder::check_cancelled()?;
+            thread::sleep(RETRY_INTERVAL);
+        }
+        if let Some(pid) = read_pid(&mut file) {
+            if pid != std::process::id() && !is_running(pid) {
+                info!("Taking over the lock of pid {pid}, which is no longer running");
+            }
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        Ok(RunLock { file })
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        // the file stays, removing it could let a third run lock a different file
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
@@ -165,11 +165,7 @@ impl LogLevel {
     }
 }
 
-pub fn log_progress(
-    start: std::time::Instant,
-    ns: usize,
-    n: usize,
-) {
+pub fn log_progress(start: std::time::Instant, ns: usize, n: usize) {
     if get_loglevel() <= LogLevel::Info {
         let elapsed_time = start.elapsed().as_millis();
         let avg_time_per_sound = elapsed_time as f32 / ns as f32;
@@ -201,6 +197,21 @@ pub fn duration(milliseconds: u128) -> String {
     }
 }
 
+pub fn size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let kilobytes = bytes as f64 / 1024.0;
+    if kilobytes < 1024.0 {
+        return format!("{kilobytes:.1}KB");
+    }
+    let megabytes = kilobytes / 1024.0;
+    if megabytes < 1024.0 {
+        return format!("{megabytes:.1}MB");
+    }
+    format!("{:.2}GB", megabytes / 1024.0)
+}
+
 pub fn duration_from_micros(micros: u128) -> String {
     if micros < 1000 {
         return format!("{micros}μs");
@@ -258,9 +269,10 @@ impl<'a> Drop for Timer<'a> {
 }
 
 pub fn display_timings() {
-    let timings: std::sync::MutexGuard<Vec<(String, std::time::Duration)>> = TIMINGS.lock().unwrap();
+    let timings: std::sync::MutexGuard<Vec<(String, std::time::Duration)>> =
+        TIMINGS.lock().unwrap();
     let mut timings: Vec<_> = timings.iter().collect();
-    timings.sort_by(|a, b| b.1.cmp(&a.1));
+    timings.sort_by_key(|b| std::cmp::Reverse(b.1));
 
     perf!("Times:");
     let mut total = 0;
@@ -10,11 +10,9 @@ mod logging;
 mod test;
 
 use std::{
-    collections::{HashMap},
-    env,
-    fs::{self, DirEntry},
-    io,
-    path::Path,
+    collections::{HashMap, HashSet},
+    env, fs, io,
+    path::{Path, PathBuf},
     process::Command,
     sync::{Arc, Mutex},
     time::Instant,
@@ -22,7 +20,8 @@ use std::{
 
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use config::{Config, Source};
+use config::Config;
+use format::Format;
 use info::Item;
 use rayon::prelude::*;
 
@@ -30,9 +29,25 @@ mod wave;
 
 use crate::logging::duration;
 
+mod cache;
+mod channels;
+mod check;
+mod clean;
 mod config;
+mod discover;
+mod encoder;
+mod format;
 mod info;
+mod lock;
+mod naming;
 mod parser;
+mod plan;
+mod probe;
+mod sources;
+mod sprite;
+mod store;
+mod verify;
+mod watch;
 
 use sha2::{Digest, Sha256};
 
@@ -55,21 +70,32 @@ fn main() -> io::Result<()> {
         parser::parse_args(&args)
     });
     logging::set_loglevel(parsed.loglevel);
+    encoder::handle_ctrlc()?;
 
-    let config = time!("Load Config", {
+    let (declared, config, config_path, command, partial) = time!("Load Config", {
         let mut args = config::Args::parse();
+        let command = args.command.take();
         if args.config.is_none() {
             args.config = Some("scodefig.jsonc".to_string());
         }
         let indir = args.indir.clone().unwrap_or(String::default());
         let config = args.config.clone().unwrap_or("scodefig.jsonc".to_string());
         let config = Path::new(&indir).join(config);
-        let config = config.to_str().unwrap_or("scodefig.jsonc");
-        debug!("Loading config from {config}");
-        let config = config::Config::load(config)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
-            .unwrap_or_default()
-            .merge_with_args(args);
+        let config_path = config.to_str().unwrap_or("scodefig.jsonc").to_string();
+        debug!("Loading config from {config_path}");
+        // syntax and other errors fail, a project without a config runs with the defaults
+        let declared = match config::Config::load(&config_path) {
+            Ok(config) => config,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("{e}, using the default settings");
+                config::Config::default()
+            }
+            Err(e) => return Err(e),
+        };
+        let mut config = declared.clone();
+        config.resolve_extends()?;
+        let config = config.merge_with_args(args);
+        let partial = config.partial;
         if config.indir.is_empty() {
             error!("No input directory specified");
             return Err(io::Error::new(
@@ -77,11 +103,47 @@ fn main() -> io::Result<()> {
                 "No input directory specified",
             ));
         }
-        config
+        (declared, config, config_path, command, partial)
     });
 
     debug!("{config}");
     debug!("{parsed:?}");
+    match command {
+        Some(config::Command::CheckConfig) => {
+            return check::run(&declared, &config, &config_path);
+        }
+        // the cache can be inspected and repaired without valid package directories
+        Some(config::Command::Cache(_)) => {}
+        _ => time!("Check Config", {
+            check::require_valid(&declared, &config)?
+        }),
+    }
+    naming::Template::from_config(&config)?;
+    let dry_run = config.dry_run.unwrap_or(false);
+    let read_only = match command {
+        Some(config::Command::Cache(ref args)) => !args.command.writes(),
+        Some(config::Command::Clean(ref args)) => args.dry_run.unwrap_or(dry_run),
+        _ => dry_run,
+    };
+    let lock = if read_only {
+        None
+    } else {
+        let wait = config.wait.unwrap_or(false);
+        Some(lock::RunLock::acquire(&config.cache_dir(), wait)?)
+    };
+    if let Some(config::Command::Cache(ref args)) = command {
+        return cache::run(&config, args);
+    }
+    if let Some(config::Command::Clean(_)) = command {
+        if partial {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "clean needs every package to know which outputs are still used, remove --packages",
+            ));
+        }
+    }
+    // dry runs and subcommands leave the sources and unfinished outputs alone
+    let normal_run = !dry_run && command.is_none();
 
     info!("Input directory: {}", config.indir);
     info!("Output directory: {}", config.outdir);
@@ -91,6 +153,12 @@ fn main() -> io::Result<()> {
         if !Path::new(&config.outdir).exists() {
             fs::create_dir_all(&config.outdir)?;
         }
+        if normal_run {
+            let removed = encoder::remove_temp_files(Path::new(&config.outdir))?;
+            if removed > 0 {
+                info!("Removed {removed} unfinished files from an interrupted run");
+            }
+        }
     });
 
     if parsed.packages.is_empty() {
@@ -98,45 +166,170 @@ fn main() -> io::Result<()> {
     } else {
         info!("Encoding packages: {:?}", parsed.packages);
     };
-    let items = time!("Create Items", { create_items(&config) })?;
-    let encode_result = time!("Encode", { encode_items(config.clone(), &items) });
+    let cache = time!("Load Cache", { load_cache(&config) });
+    let (items, skipped) = time!("Create Items", {
+        encoder::with_jobs(config.scan_jobs.or(config.jobs), || {
+            create_items(&config, &cache, normal_run)
+        })?
+    })?;
+    if let Some(config::Command::Clean(ref args)) = command {
+        if skipped > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "clean needs every source to know which outputs are still used, \
+                     {skipped} sources were skipped, run scode once to convert them to pcm"
+                ),
+            ));
+        }
+        let sprites = sprite::create_sprites(&config, &items)?;
+        return time!("Clean", { clean::run(&config, args, &items, &sprites) });
+    }
+    if dry_run {
+        let sprites = sprite::create_sprites(&config, &items)?;
+        let plan = time!("Plan", {
+            let previous = read_cache(&config.cache_dir());
+            plan::Plan::new(
+                &config.outdir,
+                &items,
+                &sprites,
+                &previous,
+                partial || skipped > 0,
+            )
+        })?;
+        plan.print();
+        if let Some(ref path) = config.plan_json {
+            plan.save_json(path)?;
+            info!("Wrote plan to {path}");
+        }
+        return Ok(());
+    }
+    let items = build(&config, items)?;
+    if let Some(config::Command::Watch(ref args)) = command {
+        success!("Done in {}", duration(now.elapsed().as_millis()));
+        // watch takes the lock again for every batch, so other runs can go in between
+        drop(lock);
+        return watch::run(config, &config_path, args, info::Map::from_vec(items));
+    }
+
+    success!("Done in {}", duration(now.elapsed().as_millis()));
+    Ok(())
+}
+
+/// Reads the cache in `dir`, starting over with an empty one when it can not be used.
+fn read_cache(dir: &Path) -> info::Map {
+    match info::Map::from_cache_bin(dir) {
+        Ok(map) => map,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("No cache yet");
+            info::Map::new()
+        }
+        Err(e) => {
+            warn!("Discarding the cache, {e}");
+            info::Map::new()
+        }
+    }
+}
+
+fn load_cache(config: &Config) -> info::Map {
+    if config.use_cache.unwrap_or(true) {
+        debug!("Loading cache");
+        debug!("Cache directory: {}", config.cache_dir().to_string_lossy());
+        read_cache(&config.cache_dir())
+    } else {
+        debug!("Skipping cache");
+        info::Map::new()
+    }
+}
+
+/// Writes the cache of `items`, the previous entries of the `unfinished` items are kept,
+/// and a run limited to some packages keeps the entries of the others.
+fn save_cache(config: &Config, items: &[Item], unfinished: &[Item]) -> io::Result<()> {
+    let mut cache = if config.partial || !unfinished.is_empty() {
+        let unfinished = info::Map::from_vec(unfinished.to_vec());
+        let mut cache = read_cache(&config.cache_dir());
+        cache.value.retain(|key, item| {
+            unfinished.value.contains_key(key)
+                || (config.partial && !config.packages.contains_key(&item.package))
+        });
+        cache
+    } else {
+        info::Map::new()
+    };
+    for item in items {
+        cache.insert(item.clone());
+    }
+    cache.save_cache_bin(&config.cache_dir())?;
+    if logging::is_debug() {
+        cache.save_cache_json(&config.cache_dir())?;
+    }
+    Ok(())
+}
+
+/// Encodes whatever is missing for the items, then writes the cache and the atlas.
+/// Returns the items with their encoder delays filled in.
+fn build(config: &Config, mut items: Vec<Item>) -> io::Result<Vec<Item>> {
+    let mut sprites = time!("Create Sprites", { sprite::create_sprites(config, &items) })?;
+    let encode_result = time!("Encode", {
+        encoder::with_jobs(config.jobs, || {
+            encode_items(config.clone(), &items, &sprites)
+        })
+        .and_then(|result| result)
+    });
     if let Err(e) = encode_result {
+        if e.kind() == io::ErrorKind::Interrupted {
+            // keep what did finish so the next run does not have to redo it,
+            // outdated outputs may not have been replaced yet
+            let members = sprite::members(&sprites);
+            let (mut finished, unfinished): (Vec<Item>, Vec<Item>) =
+                items.into_iter().partition(|item| {
+                    !item.outdated
+                        && (members.contains(item)
+                            || item
+                                .formats
+                                .iter()
+                                .all(|format| item.output_path_for(*format).exists()))
+                });
+            probe_items(&mut finished, &mut sprites);
+            // the unfinished ones keep their previous entry, which still describes their outputs
+            time!("Save Cache", { save_cache(config, &finished, &unfinished) })?;
+            warn!("Cancelled, saved the cache for {} sounds", finished.len());
+            return Err(e);
+        }
         error!("{e}");
         return Err(e);
     }
+    for item in &mut items {
+        item.outdated = false;
+    }
+    time!("Probe Delays", { probe_items(&mut items, &mut sprites) });
 
-    time!("Save Cache", {
-        let cache = info::Map::from_vec(items.clone());
-        cache.save_cache_bin()?;
-        if logging::is_debug() {
-            cache.save_cache_json()?;
-        }
+    time!("Save Cache", { save_cache(config, &items, &[]) })?;
+    let atlas = time!("Create Atlas", {
+        info::AtlasMap::from_vec(&items, &sprites)
     });
-    let atlas = time!("Create Atlas", { info::AtlasMap::from_vec(&items) });
     time!("Save Atlas", {
         // atlas.save_json_v1(".cache")?;
         atlas.save_json_v2(&config.outdir)?;
     });
-
-    success!("Done in {}", duration(now.elapsed().as_millis()));
-    Ok(())
+    Ok(items)
 }
 
 static NO_LANG: &str = "_";
+static DEFAULT_SAMPLE_RATE: u32 = 48000;
 
+/// Creates the items of every source, together with the number of sources that were skipped
+/// because they are not pcm and `convert_sources` is off.
 #[allow(clippy::too_many_lines)]
-fn create_items(config: &Config) -> io::Result<Vec<Item>> {
+fn create_items(
+    config: &Config,
+    cache: &info::Map,
+    convert_sources: bool,
+) -> io::Result<(Vec<Item>, usize)> {
     let package_names: Vec<String> = config.packages.keys().cloned().collect();
-    let indir_path = Path::new(&config.indir);
-    let join_with_indir = |package: &String| indir_path.join(package);
     let use_cache = config.use_cache.unwrap_or(true);
-    let cache = if use_cache {
-        debug!("Loading cache");
-        info::Map::from_cache_bin().unwrap_or_default()
-    } else {
-        debug!("Skipping cache");
-        info::Map::new()
-    };
+    let config_formats = config.formats();
+    let template = naming::Template::from_config(config)?;
     let package_results: Vec<Result<Vec<Result<Item, io::Error>>, io::Error>> = package_names
         .par_iter()
         .map(|package_name| {
@@ -144,83 +337,56 @@ fn create_items(config: &Config) -> io::Result<Vec<Item>> {
                 let error_message = format!("Package {package_name} not found in config");
                 return Err(io::Error::new(io::ErrorKind::NotFound, error_message));
             };
-            let package_path = join_with_indir(package_name); // Assuming join_with_indir is defined elsewhere
-            let package_sourcedir = package_config
-                .sourcedir
-                .clone()
-                .unwrap_or("sounds".to_string());
-            let package_sourcedir_path = package_path.join(Path::new(&package_sourcedir));
-            if !package_sourcedir_path.is_dir() {
-                let error_message =
-                    format!("Sourcedir: {package_sourcedir_path:?} is not a directory!",);
-                return Err(io::Error::new(io::ErrorKind::NotFound, error_message));
-            }
-
-            if package_config.languages.is_none() {
-                let files = match fs::read_dir(package_sourcedir_path) {
-                    Ok(files) => files
-                        .filter_map(std::result::Result::ok)
-                        .collect::<Vec<DirEntry>>(),
-                    Err(e) => return Err(e),
-                };
-                let package_sources = package_config.sources.clone().unwrap_or_default();
-
-                // look through the language folders
-
-                let items: Vec<Result<Item, io::Error>> = files
-                    .par_iter()
-                    .filter_map(|file| {
-                        create_item_for_file(
-                            file,
-                            &package_sources,
-                            package_config,
-                            package_name,
-                            config,
-                            use_cache,
-                            &cache,
-                            &NO_LANG.to_string(),
-                            package_config.include_flac.unwrap_or(false),
-                        )
-                    })
-                    .collect(); // Collect into Vec<Result<Item, io::Error>>
-                return Ok(items);
-            }
-            let langs = package_config.languages.clone().unwrap();
+            let package_formats = package_config.formats(&config_formats);
+            let package_sources =
+                sources::Sources::new(package_name, package_config.sources.as_ref())?;
+            let langs: Vec<(String, Option<String>)> = match package_config.languages {
+                Some(ref languages) => languages
+                    .iter()
+                    .map(|(lang, dir)| (lang.clone(), Some(dir.clone())))
+                    .collect(),
+                None => vec![(NO_LANG.to_string(), None)],
+            };
             let mut items: Vec<Result<Item, io::Error>> = Vec::new();
             for (lang, lang_dir) in langs {
-                let lang_path = package_sourcedir_path.join(Path::new(&lang_dir));
-                if !lang_path.is_dir() {
-                    let error_message = format!("Language dir: {lang_path:?} is not a directory!",);
-                    return Err(io::Error::new(io::ErrorKind::NotFound, error_message));
-                }
-                let files = match fs::read_dir(lang_path) {
-                    Ok(files) => files
-                        .filter_map(std::result::Result::ok)
-                        .collect::<Vec<DirEntry>>(),
-                    Err(e) => return Err(e),
-                };
-                let package_sources = package_config.sources.clone().unwrap_or_default();
+                let files = discover::source_files(
+                    config,
+                    package_name,
+                    package_config,
+                    &lang,
+                    lang_dir.as_deref(),
+                )?;
                 let lang_items: Vec<Result<Item, io::Error>> = files
                     .par_iter()
                     .filter_map(|file| {
+                        // the files not read yet are left alone once the user asked to stop
+                        if encoder::is_cancelled() {
+                            return None;
+                        }
                         create_item_for_file(
                             file,
                             &package_sources,
                             package_config,
                             package_name,
                             config,
+                            &template,
                             use_cache,
-                            &cache,
+                            cache,
                             &lang,
-                            package_config.include_flac.unwrap_or(false),
+                            &package_formats,
                         )
                     })
                     .collect(); // Collect into Vec<Result<Item, io::Error>>
                 items.extend(lang_items);
             }
+            for key in package_sources.unmatched() {
+                warn!("Pattern {key} in the sources of package {package_name} matches no sound");
+            }
             Ok(items)
         })
         .collect();
+    // the items are incomplete, so neither report nor convert anything
+    encoder::check_cancelled()?;
 
     let mut ok_packages: Vec<Result<Item, io::Error>> = Vec::new();
     let mut err_packages: Vec<io::Error> = Vec::new();
@@ -235,10 +401,7 @@ fn create_items(config: &Config) -> io::Result<Vec<Item>> {
         for e in err_packages {
             error!("{e}");
         }
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Error in config packages",
-        ));
+        return Err(io::Error::other("Error in config packages"));
     }
 
     let mut ok_values: Vec<Item> = Vec::new();
@@ -276,48 +439,52 @@ fn create_items(config: &Config) -> io::Result<Vec<Item>> {
             })
             .collect();
         if !fixable.is_empty() {
-            warn!("The following files are not using pcm format and or sample rate 48000:");
+            warn!("The following files are not using pcm format:");
             for file in &fixable {
                 warn!("  {}", file);
             }
+            if !convert_sources {
+                warn!("Skipping them, a normal run would convert them to pcm first");
+                return Ok((ok_values, fixable.len()));
+            }
             if !config.yes.unwrap_or(false) {
                 ask_to_reencode_source_files()?;
+                // Ctrl-C does not end the prompt, it is only seen once it is answered
+                encoder::check_cancelled()?;
             }
             let ffmpeg = config.ffmpeg.clone().unwrap_or("ffmpeg".to_string());
             reencode_source_files(&fixable, &ffmpeg)?;
             info!("Some files had be reencoded, rerunning the program to recheck the source files");
-            return create_items(config);
+            return create_items(config, cache, convert_sources);
         }
 
         for e in err_values {
             error!("{e}");
         }
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Error in source items",
-        ));
+        return Err(io::Error::other("Error in source items"));
     }
 
-    Ok(ok_values)
+    Ok((ok_values, 0))
 }
 
-#[allow(clippy::fn_params_excessive_bools, clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments)]
 fn create_item_for_file(
-    file: &DirEntry,
-    package_sources: &HashMap<String, Source>,
+    file: &discover::SourceFile,
+    package_sources: &sources::Sources,
     package_config: &config::Package,
     package_name: &String,
     config: &config::Config,
+    template: &naming::Template,
     use_cache: bool,
     cache: &info::Map,
     lang: &String,
-    include_flac: bool,
+    package_formats: &[Format],
 ) -> Option<Result<Item, io::Error>> {
-    let file_buf = file.path();
-    if !file_buf.is_file() {
+    if !file.path.is_file() {
         return None; // Skip directories or non-files
     }
-    let file_path = file_buf.as_path();
+    let file_path = file.path.as_path();
+    let inherited_from = file.inherited_from.as_deref();
     let file_path_str = file_path.to_string_lossy();
     let extension = file_path.extension().unwrap_or_default().to_string_lossy();
     if extension != "wav" {
@@ -333,22 +500,59 @@ fn create_item_for_file(
         }
     };
 
+    let name = file.name.clone();
+    let source = package_sources.get(&name);
+    let source = source.as_ref();
+    let target_bitrate = source
+        .and_then(|settings| settings.bitrate)
+        .or(package_config.bitrate)
+        .unwrap_or(config.bitrate);
+    let formats = source.map_or_else(
+        || package_formats.to_vec(),
+        |settings| settings.formats(package_formats),
+    );
+    let target_sample_rate = source
+        .and_then(|settings| settings.samplerate)
+        .or(package_config.samplerate)
+        .or(config.samplerate)
+        .unwrap_or(DEFAULT_SAMPLE_RATE);
+    let fingerprint = info::Settings {
+        bitrate: target_bitrate,
+        channels: source.and_then(|settings| settings.channels),
+        downmix: source.and_then(|settings| settings.downmix.as_ref()),
+        formats: &formats,
+        sample_rate: target_sample_rate,
+        ogg_quality: config.ogg_quality,
+        output_name: config.output_name.as_deref(),
+    }
+    .fingerprint();
+
+    let cached = match inherited_from {
+        Some(_) => cache.get_inherited(&file_path_str, package_name),
+        None => cache.get(&file_path_str),
+    };
     // should check the --skip-cache flag
     if use_cache {
-        let cached = cache.get(&file_path_str);
         if let Some(cached) = cached {
             debug!("Cached: {file_path_str}");
-            if modification_date == cached.modification_date {
-                return Some(Ok(cached.clone()));
+            if modification_date == cached.modification_date && fingerprint == cached.fingerprint {
+                // the cache may have been written from another directory or for another outdir
+                let mut item = cached.clone();
+                item.path = file_path_str.to_string();
+                match Path::new(&config.outdir).canonicalize() {
+                    Ok(outdir) => {
+                        item.output_path =
+                            outdir.join(&item.outfile).to_string_lossy().into_owned();
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+                return Some(Ok(item));
             }
         }
     }
 
-    let name = file.file_name().to_string_lossy().replace(".wav", "");
-
     // Wrap fs::read and wave processing in a Result::map_err to convert any error to io::Error
     let result = fs::read(file_path)
-        .map_err(std::convert::Into::into)
         .and_then(|buffer| {
             wave::Data::from_buffer(&buffer)
                 .map_err(|e| {
@@ -359,68 +563,101 @@ fn create_item_for_file(
                 .and_then(|wave| {
                     // Use and_then to allow returning Err directly
                     let sample_rate = wave.format.sample_rate;
-                    if sample_rate == 48000 {
-                        let input_samples = wave.num_samples;
-                        let input_channels = wave.format.num_channels;
-
-                        let full = Sha256::digest(&buffer);
-                        let string = format!("{:x}", full);
-                        let hash = string[..10].to_string();
-
-                        // let mut hasher = DefaultHasher::new();
-                        // buffer.hash(&mut hasher);
-                        // let hash = hasher.finish().to_string();
-                        // // convert to be maximum15 characters
-                        // let hash = &hash[..15];
-
-                        let (target_bitrate, target_channels) =
-                            package_sources.get(&name).map_or_else(
-                                || {
-                                    (
-                                        package_config.bitrate.unwrap_or(config.bitrate),
-                                        input_channels,
-                                    )
-                                },
-                                |settings| {
-                                    (
-                                        settings.bitrate.unwrap_or(
-                                            package_config.bitrate.unwrap_or(config.bitrate),
-                                        ),
-                                        settings.channels.unwrap_or(input_channels),
-                                    )
-                                },
-                            );
-
-                        let outfile = format!("{target_bitrate}kb.{target_channels}ch.{hash}.webm");
-                        let output_path = Path::new(&config.outdir).canonicalize()?.join(&outfile);
-
-                        Ok(Item {
-                            // Ensure to wrap the Item in Ok
-                            path: file_path_str.to_string(),
-                            name,
-                            outfile,
-                            package: package_name.to_string(),
-                            lang: lang.to_string(),
-                            sample_rate,
-                            num_samples: input_samples,
-                            input_channels,
-                            target_channels,
-                            modification_date,
-                            bitrate: target_bitrate,
-                            output_path: output_path.to_string_lossy().into_owned(),
-                            include_flac,
-                        })
-                    } else {
+                    let input_samples = wave.num_samples;
+                    let input_channels = wave.format.num_channels;
+
+                    let full = Sha256::digest(&buffer);
+                    let content_hash = format!("{:x}", full);
+
+                    // let mut hasher = DefaultHasher::new();
+                    // buffer.hash(&mut hasher);
+                    // let hash = hasher.finish().to_string();
+                    // // convert to be maximum15 characters
+                    // let hash = &hash[..15];
+
+                    let target_channels = source
+                        .and_then(|settings| settings.channels)
+                        .unwrap_or(input_channels);
+                    let explicit_downmix = source.and_then(|settings| settings.downmix.clone());
+                    if let Err(message) = channels::validate(
+                        input_channels,
+                        target_channels,
+                        explicit_downmix.as_ref(),
+                    ) {
+                        let message = format!("{message} for file: {file_path_str}");
+                        return Err(io::Error::new(io::ErrorKind::Unsupported, message));
+                    }
+                    let downmix = explicit_downmix.or_else(|| {
+                        let mask = channels::resolve_mask(input_channels, wave.format.channel_mask);
+                        channels::default_downmix(input_channels, mask, target_channels)
+                    });
+                    if let Some(format) = formats
+                        .iter()
+                        .find(|format| !format.supports_sample_rate(target_sample_rate))
+                    {
                         let message = format!(
-                            "Sample rate {sample_rate} is not 48000 for file: {file_path_str}"
+                            "Sample rate {target_sample_rate} is not supported by {format} for file: {file_path_str}"
                         );
-                        Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+                        return Err(io::Error::new(io::ErrorKind::Unsupported, message));
                     }
+
+                    let outfile = template.stem(&naming::Values {
+                        package: package_name,
+                        lang,
+                        name: &name,
+                        bitrate: target_bitrate,
+                        channels: target_channels,
+                        sample_rate: target_sample_rate,
+                        hash: &content_hash,
+                    });
+                    let output_path = Path::new(&config.outdir).canonicalize()?.join(&outfile);
+                    // with names that do not contain the hash or every setting, the old
+                    // outputs can have the same name as the new ones, and without a cache
+                    // entry there is no telling what an existing output was encoded from
+                    let outdated = match cached {
+                        Some(cached) => {
+                            cached.hash != content_hash || cached.fingerprint != fingerprint
+                        }
+                        None => !template.names_content(),
+                    };
+
+                    Ok(Item {
+                        // Ensure to wrap the Item in Ok
+                        path: file_path_str.to_string(),
+                        name,
+                        outfile,
+                        hash: content_hash,
+                        package: package_name.to_string(),
+                        lang: lang.to_string(),
+                        sample_rate,
+                        target_sample_rate,
+                        num_samples: resampled_length(input_samples, sample_rate, target_sample_rate),
+                        input_channels,
+                        target_channels,
+                        downmix,
+                        modification_date,
+                        bitrate: target_bitrate,
+                        output_path: output_path.to_string_lossy().into_owned(),
+                        formats,
+                        fingerprint,
+                        delays: HashMap::new(),
+                        inherited_from: inherited_from.map(str::to_string),
+                        outdated,
+                    })
                 })
         });
     Some(result)
 }
 
+/// Number of samples a sound will have after resampling it to `target_rate`.
+fn resampled_length(num_samples: usize, sample_rate: u32, target_rate: u32) -> usize {
+    if sample_rate == target_rate || sample_rate == 0 {
+        return num_samples;
+    }
+    let scaled = num_samples as u64 * u64::from(target_rate) + u64::from(sample_rate) / 2;
+    (scaled / u64::from(sample_rate)) as usize
+}
+
 fn ask_to_reencode_source_files() -> io::Result<()> {
     loop {
         success!("Do you want to reencode the source files? (y/n)");
@@ -442,17 +679,16 @@ fn ask_to_reencode_source_files() -> io::Result<()> {
 
 fn reencode_source_files(files: &[String], ffmpeg: &str) -> io::Result<()> {
     // Use a combination of `map` and `collect` to handle errors
-    let results: Vec<io::Result<()>> = time!("Convert sample rates", {
+    let results: Vec<io::Result<()>> = time!("Convert to pcm", {
         files
             .par_iter()
             .map(|file| {
-                let converted = file.replace(".wav", ".48000.wav");
+                encoder::check_cancelled()?;
+                let converted = file.replace(".wav", ".pcm.wav");
                 debug!("Converting file: {file} to {converted}");
                 let output = Command::new(ffmpeg)
                     .arg("-i")
                     .arg(file)
-                    .arg("-ar")
-                    .arg("48000")
                     .arg(&converted)
                     .arg("-acodec")
                     .arg("pcm_s24le")
@@ -462,7 +698,7 @@ fn reencode_source_files(files: &[String], ffmpeg: &str) -> io::Result<()> {
                 // Handle command execution error
                 if !output.status.success() {
                     let error = String::from_utf8_lossy(&output.stderr);
-                    return Err(io::Error::new(io::ErrorKind::Other, error.to_string()));
+                    return Err(io::Error::other(error.to_string()));
                 }
                 fs::remove_file(file)?;
                 fs::rename(&converted, file)?;
@@ -470,6 +706,8 @@ fn reencode_source_files(files: &[String], ffmpeg: &str) -> io::Result<()> {
             })
             .collect()
     });
+    // files that were not converted yet are still found by the next run
+    encoder::check_cancelled()?;
     let errors = results
         .iter()
         .filter_map(|result| result.as_ref().err())
@@ -486,44 +724,107 @@ fn reencode_source_files(files: &[String], ffmpeg: &str) -> io::Result<()> {
     Ok(())
 }
 
-fn encode_items(config: Config, items: &[Item]) -> io::Result<()> {
+/// The `formats` whose output at `output_path_for` no one claimed yet, claiming them.
+fn claim_outputs(
+    claimed: &mut HashSet<PathBuf>,
+    formats: &[Format],
+    output_path_for: impl Fn(Format) -> PathBuf,
+) -> Vec<Format> {
+    formats
+        .iter()
+        .copied()
+        .filter(|format| claimed.insert(output_path_for(*format)))
+        .collect()
+}
+
+fn encode_items(config: Config, items: &[Item], sprites: &[sprite::Sprite]) -> io::Result<()> {
+    let members = sprite::members(sprites);
     let items_to_encode: Vec<&info::Item> = time!("Encode: Check need", {
         items
             .par_iter()
             .filter(|info| {
-                // return true to keep
-                let path = info.output_path.clone();
-
-                if config.include_webm.unwrap_or(true) && !Path::new(&path).exists() {
-                    return true;
-                }
-
-                if config.include_mp4.unwrap_or(false)
-                    && !Path::new(&path.replace("webm", "mp4")).exists()
-                {
-                    return true;
-                }
-
-                if config.include_opus.unwrap_or(false)
-                    && !Path::new(&path.replace("webm", "opus")).exists()
-                {
-                    return true;
+                // sprite members are encoded as part of their sprite
+                if members.contains(info) {
+                    return false;
                 }
-
-                if config.include_flac.unwrap_or(false)
-                    && !Path::new(&path.replace("webm", "flac")).exists()
-                {
-                    return true;
-                }
-
-                if info.include_flac && !Path::new(&path.replace("webm", "flac")).exists() {
-                    return true;
-                }
-                false
+                // keep the item if any of the formats it resolves to is missing
+                info.outdated
+                    || info
+                        .formats
+                        .iter()
+                        .any(|format| !info.output_path_for(*format).exists())
             })
-            // update this to filter where all that is supposed to be encoded doesnt exist
             .collect()
     });
+    let sprites_to_encode: Vec<&sprite::Sprite> = sprites
+        .iter()
+        .filter(|sprite| {
+            sprite
+                .formats
+                .iter()
+                .any(|format| !sprite.output_path_for(*format).exists())
+        })
+        .collect();
+    // a source inherited with the same settings has the same outputs in both packages,
+    // so each output is fetched or encoded by the first item or sprite that has it
+    let mut claimed: HashSet<PathBuf> = HashSet::new();
+    let unique_items: Vec<Item> = items_to_encode
+        .into_iter()
+        .filter_map(|item| {
+            let formats = claim_outputs(&mut claimed, &item.formats, |format| {
+                item.output_path_for(format)
+            });
+            (!formats.is_empty()).then(|| Item {
+                formats,
+                ..item.clone()
+            })
+        })
+        .collect();
+    let unique_sprites: Vec<sprite::Sprite> = sprites_to_encode
+        .into_iter()
+        .filter_map(|sprite| {
+            let formats = claim_outputs(&mut claimed, &sprite.formats, |format| {
+                sprite.output_path_for(format)
+            });
+            (!formats.is_empty()).then(|| sprite::Sprite {
+                formats,
+                ..sprite.clone()
+            })
+        })
+        .collect();
+    let items_to_encode: Vec<&Item> = unique_items.iter().collect();
+    let sprites_to_encode: Vec<&sprite::Sprite> = unique_sprites.iter().collect();
+    let store = store::Store::from_config(&config);
+    let fetched: HashSet<PathBuf> = match store {
+        Some(ref store) => time!("Encode: Fetch from store", {
+            fetch_from_store(
+                store,
+                config.ogg_quality,
+                &items_to_encode,
+                &sprites_to_encode,
+            )
+        }),
+        None => HashSet::new(),
+    };
+    let missing =
+        |path: PathBuf, outdated: bool| !fetched.contains(&path) && (outdated || !path.exists());
+    let items_to_encode: Vec<&info::Item> = items_to_encode
+        .into_iter()
+        .filter(|item| {
+            item.formats
+                .iter()
+                .any(|format| missing(item.output_path_for(*format), item.outdated))
+        })
+        .collect();
+    let sprites_to_encode: Vec<&sprite::Sprite> = sprites_to_encode
+        .into_iter()
+        .filter(|sprite| {
+            sprite
+                .formats
+                .iter()
+                .any(|format| missing(sprite.output_path_for(*format), false))
+        })
+        .collect();
     time!("Encode: Check ffmpeg exists", {
         let ffmpeg = config.ffmpeg.clone().unwrap_or("ffmpeg".to_string());
         // check if ffmpeg is installed
@@ -535,45 +836,304 @@ fn encode_items(config: Config, items: &[Item]) -> io::Result<()> {
             ));
         }
     });
-    let results = time!("Encode: Sounds", {
+    let options = encoder::EncodeOptions::from_config(&config);
+    let limiter = config.max_memory.map(encoder::MemoryLimiter::new);
+    let mut results = time!("Encode: Sounds", {
         info!(
             "Encoding {} sounds out of {}",
             items_to_encode.len(),
             items.len()
         );
-        encode_with_progress(
-            &items_to_encode,
-            &config.ffmpeg.unwrap_or("ffmpeg".to_string()),
-            config.include_mp4.unwrap_or(false),
-            config.include_flac.unwrap_or(false),
-            config.include_webm.unwrap_or(true),
-            config.include_opus.unwrap_or(false),
-        )
+        encode_with_progress(&items_to_encode, &options, limiter.as_ref())
+    });
+    let sprite_results: Vec<io::Result<()>> = time!("Encode: Sprites", {
+        if !sprites_to_encode.is_empty() {
+            info!(
+                "Encoding {} sprites out of {}",
+                sprites_to_encode.len(),
+                sprites.len()
+            );
+        }
+        sprites_to_encode
+            .par_iter()
+            .map(|sprite| {
+                let _permit = limiter
+                    .as_ref()
+                    .map(|limiter| limiter.acquire(sprite::estimate_memory(sprite)));
+                sprite::encode(&options, sprite)
+            })
+            .collect()
     });
+    let encoded = encoded_outputs(
+        config.ogg_quality,
+        &items_to_encode,
+        &results,
+        &sprites_to_encode,
+        &sprite_results,
+    );
+    results.extend(sprite_results);
+    if encoder::is_cancelled() {
+        return Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled"));
+    }
+    let failed: HashSet<PathBuf> = if config.verify.unwrap_or(false) {
+        let tolerance = config.verify_tolerance.unwrap_or(verify::DEFAULT_TOLERANCE);
+        time!("Encode: Verify", {
+            verify_outputs(
+                &options,
+                &items_to_encode,
+                &sprites_to_encode,
+                &encoded,
+                tolerance,
+            )
+        })?
+    } else {
+        HashSet::new()
+    };
+    // the good outputs are published before failing, so failures elsewhere do not hold them back
+    if let Some(store) = store.as_ref().filter(|store| store.publish) {
+        let passed: Vec<StoreOutput> = encoded
+            .into_iter()
+            .filter(|(_, _, path)| !failed.contains(path))
+            .collect();
+        time!("Encode: Publish to store", {
+            publish_to_store(store, &passed)
+        });
+    }
     let errors = results
         .par_iter()
         .filter_map(|result| result.as_ref().err())
         .collect::<Vec<&io::Error>>();
     if !errors.is_empty() {
-        for error in errors {
+        let timed_out = errors
+            .iter()
+            .filter(|error| error.kind() == io::ErrorKind::TimedOut)
+            .count();
+        for error in &errors {
             error!("{error}");
         }
+        if timed_out == errors.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{timed_out} files timed out while encoding"),
+            ));
+        }
+        if timed_out > 0 {
+            error!("{timed_out} files timed out while encoding");
+        }
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "Some files failed to encode",
         ));
     }
+    if !failed.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} outputs failed verification", failed.len()),
+        ));
+    }
     Ok(())
 }
 
-#[allow(clippy::fn_params_excessive_bools)]
+/// An output in the shared store: its key, format and local path.
+type StoreOutput = (String, Format, PathBuf);
+
+/// Downloads the outputs the store already has, and returns their paths.
+fn fetch_from_store(
+    store: &store::Store,
+    ogg_quality: Option<f32>,
+    items: &[&Item],
+    sprites: &[&sprite::Sprite],
+) -> HashSet<PathBuf> {
+    let mut wanted: Vec<StoreOutput> = Vec::new();
+    for item in items {
+        for format in &item.formats {
+            let path = item.output_path_for(*format);
+            if item.outdated || !path.exists() {
+                wanted.push((store::item_key(item, *format, ogg_quality), *format, path));
+            }
+        }
+    }
+    for sprite in sprites {
+        for format in &sprite.formats {
+            let path = sprite.output_path_for(*format);
+            if !path.exists() {
+                wanted.push((
+                    store::sprite_key(sprite, *format, ogg_quality),
+                    *format,
+                    path,
+                ));
+            }
+        }
+    }
+    if wanted.is_empty() {
+        return HashSet::new();
+    }
+    let fetched: HashSet<PathBuf> = wanted
+        .par_iter()
+        .filter_map(
+            |(key, format, path)| match store.fetch(key, *format, path) {
+                Ok(true) => {
+                    debug!("Fetched {} from the store", path.to_string_lossy());
+                    Some(path.clone())
+                }
+                Ok(false) => None,
+                Err(e) => {
+                    warn!(
+                        "Could not fetch {} from the store: {e}",
+                        path.to_string_lossy()
+                    );
+                    None
+                }
+            },
+        )
+        .collect();
+    info!(
+        "Fetched {} of {} outputs from the store at {}",
+        fetched.len(),
+        wanted.len(),
+        store.describe()
+    );
+    fetched
+}
+
+/// The outputs of the items and sprites that encoded without errors.
+fn encoded_outputs(
+    ogg_quality: Option<f32>,
+    items: &[&Item],
+    item_results: &[io::Result<()>],
+    sprites: &[&sprite::Sprite],
+    sprite_results: &[io::Result<()>],
+) -> Vec<StoreOutput> {
+    let mut outputs: Vec<StoreOutput> = Vec::new();
+    for (item, _) in items
+        .iter()
+        .zip(item_results)
+        .filter(|(_, result)| result.is_ok())
+    {
+        for format in &item.formats {
+            let key = store::item_key(item, *format, ogg_quality);
+            outputs.push((key, *format, item.output_path_for(*format)));
+        }
+    }
+    for (sprite, _) in sprites
+        .iter()
+        .zip(sprite_results)
+        .filter(|(_, result)| result.is_ok())
+    {
+        for format in &sprite.formats {
+            let key = store::sprite_key(sprite, *format, ogg_quality);
+            outputs.push((key, *format, sprite.output_path_for(*format)));
+        }
+    }
+    outputs
+}
+
+/// Uploads outputs to the store. Failures are only logged, the local outputs are fine.
+fn publish_to_store(store: &store::Store, outputs: &[StoreOutput]) {
+    let published = outputs
+        .par_iter()
+        .filter(|(_, _, path)| path.exists())
+        .filter(
+            |(key, format, path)| match store.publish(key, *format, path) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!(
+                        "Could not publish {} to the store: {e}",
+                        path.to_string_lossy()
+                    );
+                    false
+                }
+            },
+        )
+        .count();
+    if published > 0 {
+        info!("Published {published} outputs to the store");
+    }
+}
+
+/// Checks every output encoded in this run and removes the ones that do not match
+/// their settings, so that the next run encodes them again.
+/// Returns the outputs that failed, and only fails itself when ffprobe is missing.
+fn verify_outputs(
+    options: &encoder::EncodeOptions,
+    items: &[&Item],
+    sprites: &[&sprite::Sprite],
+    encoded: &[StoreOutput],
+    tolerance: u32,
+) -> io::Result<HashSet<PathBuf>> {
+    let ffprobe_check = Command::new(&options.ffprobe).arg("-version").output();
+    if let Err(e) = ffprobe_check {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("ffprobe not found at {}: {e}", &options.ffprobe),
+        ));
+    }
+    let mut outputs: Vec<(PathBuf, verify::Expected)> = Vec::new();
+    for item in items {
+        for format in &item.formats {
+            outputs.push((
+                item.output_path_for(*format),
+                verify::Expected {
+                    format: *format,
+                    channels: item.target_channels,
+                    sample_rate: item.target_sample_rate,
+                    num_samples: item.num_samples,
+                    sources: vec![PathBuf::from(&item.path)],
+                },
+            ));
+        }
+    }
+    for sprite in sprites {
+        for format in &sprite.formats {
+            outputs.push((
+                sprite.output_path_for(*format),
+                verify::Expected {
+                    format: *format,
+                    channels: sprite.target_channels,
+                    sample_rate: sprite.target_sample_rate,
+                    num_samples: sprite.num_samples,
+                    sources: sprite
+                        .members
+                        .iter()
+                        .map(|member| PathBuf::from(&member.path))
+                        .collect(),
+                },
+            ));
+        }
+    }
+    // the outputs of sounds that failed to encode are reported as such already
+    let encoded: HashSet<&PathBuf> = encoded.iter().map(|(_, _, path)| path).collect();
+    outputs.retain(|(path, _)| encoded.contains(path));
+    info!("Verifying {} outputs", outputs.len());
+
+    let failures: Vec<(PathBuf, Vec<String>)> = outputs
+        .par_iter()
+        .filter_map(
+            |(path, expected)| match verify::check(options, path, expected, tolerance) {
+                Ok(problems) if problems.is_empty() => None,
+                Ok(problems) => {
+                    if let Err(e) = fs::remove_file(path) {
+                        warn!("Could not remove {}: {e}", path.to_string_lossy());
+                    }
+                    Some((path.clone(), problems))
+                }
+                Err(e) => Some((path.clone(), vec![format!("Could not be verified: {e}")])),
+            },
+        )
+        .collect();
+    for (path, problems) in &failures {
+        error!("Verification failed for file: {}", path.to_string_lossy());
+        for problem in problems {
+            error!("  - {problem}");
+        }
+    }
+    Ok(failures.into_iter().map(|(path, _)| path).collect())
+}
+
 fn encode_with_progress(
     sounds: &Vec<&info::Item>,
-    ffmpeg: &str,
-    include_mp4: bool,
-    include_flac: bool,
-    include_webm: bool,
-    include_opus: bool,
+    options: &encoder::EncodeOptions,
+    limiter: Option<&encoder::MemoryLimiter>,
 ) -> Vec<io::Result<()>> {
     let n = sounds.len();
     if n > 0 {
@@ -584,14 +1144,10 @@ fn encode_with_progress(
             .map(|info| {
                 *ne.lock().unwrap() += 1;
                 logging::log_progress(start, *ne.lock().unwrap(), n);
-                encode_one_item(
-                    ffmpeg,
-                    info,
-                    include_mp4,
-                    include_flac || info.include_flac,
-                    include_webm,
-                    include_opus,
-                )
+                let _permit = limiter.map(|limiter| {
+                    limiter.acquire(encoder::estimate_memory(Path::new(&info.path)))
+                });
+                encode_one_item(options, info)
             })
             .collect();
         logging::log_progress(start, n, n);
@@ -601,15 +1157,7 @@ fn encode_with_progress(
     }
 }
 
-#[allow(clippy::too_many_lines, clippy::fn_params_excessive_bools)]
-fn encode_one_item(
-    ffmpeg: &str,
-    info: &info::Item,
-    include_mp4: bool,
-    include_flac: bool,
-    include_webm: bool,
-    include_opus: bool,
-) -> io::Result<()> {
+fn encode_one_item(options: &encoder::EncodeOptions, info: &info::Item) -> io::Result<()> {
     let infile = Path::new(&info.path);
     let infile = match infile.canonicalize() {
         Ok(path) => path,
@@ -623,13 +1171,7 @@ fn encode_one_item(
     .to_string_lossy()
     .to_string();
 
-    let out_path = Path::new(&info.output_path);
-    let outfile = out_path.to_string_lossy().to_string();
-
     debug!("Encoding {infile}");
-    debug!("Encoding {outfile}");
-
-    let is_stereo_to_mono = info.input_channels == 2 && info.target_channels == 1;
 
     // When specifying the bitrate in FFmpeg for audio encoding,
     // you should specify the total bitrate for all channels, not per channel.
@@ -638,146 +1180,111 @@ fn encode_one_item(
     // this bitrate is the total bitrate used for the audio stream,
     // whether it's mono, stereo, or multi-channel audio.
     let bitrate = info.bitrate * u32::from(info.target_channels);
+    let duration = info.num_samples as f64 / f64::from(info.target_sample_rate.max(1));
 
-    let mut command = Command::new(ffmpeg);
-    let command = command
-        .arg("-i")
-        .arg(infile)
-        .arg("-b:a")
-        .arg(bitrate.to_string() + "k")
-        .arg("-ar")
-        .arg("48000")
-        // remove any metadata
-        .arg("-map_metadata")
-        .arg("-1")
-        .arg("-y");
-    // opus codec
-    let command = if is_stereo_to_mono {
-        command
-            // mono mixdown with gain adjustment
-            .arg("-af")
-            .arg("pan=mono|c0=0.5*c0+0.5*c1")
-            .arg("-ac")
-            .arg("1")
-    } else {
-        command
-    };
-
-    if include_webm {
-        let result = command.arg("-c:a").arg("libopus").arg(&outfile).output();
-
-        if let Err(e) = result {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("ffmpeg execution failed when encoding webm file {outfile} with error {e}",),
-            ));
+    for format in &info.formats {
+        let out_path = info.output_path_for(*format);
+        if out_path.exists() && !info.outdated {
+            continue;
         }
-
-        let output = result.unwrap();
-        let status = output.status;
-        if !status.success() {
-            warn!("command: {command:?}");
-            warn!("webm_output: {output:?}");
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding webm file {outfile} with status {status}",
-                ),
-            ));
-        }
-    }
-
-    if include_opus {
-        let outfile = outfile.clone().replace("webm", "opus");
+        let outfile = out_path.to_string_lossy().to_string();
         debug!("Encoding {outfile}");
 
-        // write the flac file
-        let result = command
-            .arg("-c:a")
-            .arg("libopus")
-            .arg(outfile.clone())
-            .output();
-        if let Err(e) = result {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding flac file {} with error {e}",
-                    outfile.clone()
-                ),
-            ));
+        let mut command = Command::new(&options.ffmpeg);
+        command
+            .arg("-i")
+            .arg(&infile)
+            .arg("-b:a")
+            .arg(bitrate.to_string() + "k")
+            .arg("-ar")
+            .arg(info.target_sample_rate.to_string())
+            // remove any metadata
+            .arg("-map_metadata")
+            .arg("-1")
+            .arg("-y");
+        if let Some(ref matrix) = info.downmix {
+            // mixdown with gain adjustment
+            command
+                .arg("-af")
+                .arg(channels::pan_filter(matrix))
+                .arg("-ac")
+                .arg(info.target_channels.to_string());
+        } else if info.target_channels != info.input_channels {
+            command.arg("-ac").arg(info.target_channels.to_string());
         }
-        let status = result.unwrap().status;
+        command.args(options.thread_args());
+        command.args(format.codec_args(info.target_channels, options.ogg_quality));
+
+        let output = options
+            .run_to_file(&mut command, *format, &out_path, duration)
+            .map_err(|e| {
+                // keep the kind so that hung processes can be told apart from codec failures
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "ffmpeg execution failed when encoding {format} file {outfile} with error {e}"
+                    ),
+                )
+            })?;
+        let status = output.status;
         if !status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding flac file {outfile} with status {status}",
-                ),
-            ));
+            warn!("command: {command:?}");
+            warn!("{format}_output: {output:?}");
+            return Err(io::Error::other(format!(
+                "ffmpeg execution failed when encoding {format} file {outfile} with status {status}",
+            )));
         }
     }
 
-    if include_mp4 {
-        let outfile = outfile.clone().replace("webm", "mp4");
-        debug!("Encoding {outfile}");
+    Ok(())
+}
 
-        // write the mp4 file
-        let result = command
-            .arg("-c:a")
-            .arg("aac")
-            .arg("-movflags")
-            .arg("+faststart")
-            .arg(outfile.clone())
-            .output();
-        if let Err(e) = result {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding mp4 file {} with error {e}",
-                    outfile.clone()
-                ),
-            ));
-        }
-        let status = result.unwrap().status;
-        if !status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding mp4 file {outfile} with status {status}",
-                ),
-            ));
+/// Reads the encoder priming and padding of every output that does not have it yet.
+fn probe_items(items: &mut [Item], sprites: &mut [sprite::Sprite]) {
+    let members = sprite::members(sprites);
+    sprites.par_iter_mut().for_each(|sprite| {
+        for format in sprite.formats.clone() {
+            let path = sprite.output_path_for(format);
+            if !path.exists() {
+                continue;
+            }
+            match probe::delay(&path, format, sprite.num_samples, sprite.target_sample_rate) {
+                Ok(delay) => {
+                    sprite.delays.insert(format, delay);
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not read encoder delay of {}: {e}",
+                        path.to_string_lossy()
+                    );
+                }
+            }
         }
-    }
-
-    if include_flac {
-        let outfile = outfile.clone().replace("webm", "flac");
-        debug!("Encoding {outfile}");
-
-        // write the flac file
-        let result = command
-            .arg("-c:a")
-            .arg("flac")
-            .arg(outfile.clone())
-            .output();
-        if let Err(e) = result {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding flac file {} with error {e}",
-                    outfile.clone()
-                ),
-            ));
+    });
+    items.par_iter_mut().for_each(|item| {
+        if members.contains(item) {
+            return;
         }
-        let status = result.unwrap().status;
-        if !status.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "ffmpeg execution failed when encoding flac file {outfile} with status {status}",
-                ),
-            ));
+        let formats = item.formats.clone();
+        for format in formats {
+            if item.delays.contains_key(&format) {
+                continue;
+            }
+            let path = item.output_path_for(format);
+            if !path.exists() {
+                continue;
+            }
+            match probe::delay(&path, format, item.num_samples, item.target_sample_rate) {
+                Ok(delay) => {
+                    item.delays.insert(format, delay);
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not read encoder delay of {}: {e}",
+                        path.to_string_lossy()
+                    );
+                }
+            }
         }
-    }
-
-    Ok(())
+    });
 }
@@ -0,0 +1,268 @@
+// Output file names.
+// Names come from a template like `{bitrate}kb.{channels}ch.{samplerate}hz.{hash:10}.{ext}`,
+// which is checked up front so that two different outputs can never get the same name.
+// The default name leaves out `{samplerate}hz.` for the default sample rate, so outputs
+// keep the names they had before the sample rate could be set.
+
+use std::io;
+
+use crate::config::Config;
+
+/// Length of `{hash}` without an explicit length.
+const DEFAULT_HASH_LENGTH: usize = 10;
+/// Shorter hashes are too likely to collide between different sources,
+/// they can still be used to shard outputs into subdirectories.
+const MIN_HASH_LENGTH: usize = 8;
+/// A sha256 in hex.
+const MAX_HASH_LENGTH: usize = 64;
+const EXTENSION_SUFFIX: &str = ".{ext}";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Package,
+    Lang,
+    Name,
+    Bitrate,
+    Channels,
+    SampleRate,
+    Hash(usize),
+    /// Only rendered when the output is not at the default sample rate.
+    UnlessDefaultSampleRate(Vec<Part>),
+}
+
+/// A parsed `output_name` template.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+    /// Whether names are made of the content and every setting that changes it,
+    /// so an existing output with the name is always up to date.
+    by_content: bool,
+}
+
+/// What a name is made of.
+pub struct Values<'a> {
+    pub package: &'a str,
+    pub lang: &'a str,
+    pub name: &'a str,
+    pub bitrate: u32,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// sha256 in hex of the source, or of the members of a sprite.
+    pub hash: &'a str,
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+    let (key, length) = match placeholder.split_once(':') {
+        Some((key, length)) => (key, Some(length)),
+        None => (placeholder, None),
+    };
+    if length.is_some() && key != "hash" {
+        return Err(format!(
+            "Only {{hash}} takes a length, not {{{placeholder}}}"
+        ));
+    }
+    let part = match key {
+        "package" => Part::Package,
+        "lang" => Part::Lang,
+        "name" => Part::Name,
+        "bitrate" => Part::Bitrate,
+        "channels" => Part::Channels,
+        "samplerate" => Part::SampleRate,
+        "hash" => {
+            let length = match length {
+                Some(length) => length
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid hash length in {{{placeholder}}}"))?,
+                None => DEFAULT_HASH_LENGTH,
+            };
+            if !(1..=MAX_HASH_LENGTH).contains(&length) {
+                return Err(format!(
+                    "Hash length must be between 1 and {MAX_HASH_LENGTH}, got {length}"
+                ));
+            }
+            Part::Hash(length)
+        }
+        "ext" => return Err("{ext} can only be used at the end, as .{ext}".to_string()),
+        _ => return Err(format!("Unknown placeholder {{{placeholder}}}")),
+    };
+    Ok(part)
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let Some(stem) = template.strip_suffix(EXTENSION_SUFFIX) else {
+            return Err(format!("Output name {template} must end with .{{ext}}"));
+        };
+        let mut parts: Vec<Part> = Vec::new();
+        let mut rest = stem;
+        while !rest.is_empty() {
+            let Some(start) = rest.find(['{', '}']) else {
+                parts.push(Part::Text(rest.to_string()));
+                break;
+            };
+            if rest[start..].starts_with('}') {
+                return Err(format!("Unmatched }} in output name {template}"));
+            }
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("Unclosed {{ in output name {template}"));
+            };
+            parts.push(parse_placeholder(&rest[start + 1..start + end])?);
+            rest = &rest[start + end + 1..];
+        }
+
+        let text: String = parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        if text.contains('\\') || stem.starts_with('/') || stem.ends_with('/') {
+            return Err(format!(
+                "Output name {template} must be a relative path using / between directories"
+            ));
+        }
+        if stem
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "..")
+        {
+            return Err(format!(
+                "Output name {template} can not have empty or .. directories"
+            ));
+        }
+
+        // a name is unique when it either identifies the source, or the content
+        // together with every setting that changes the encoded audio
+        let has = |part: &Part| parts.contains(part);
+        let has_hash = parts
+            .iter()
+            .any(|part| matches!(part, Part::Hash(length) if *length >= MIN_HASH_LENGTH));
+        let by_source = has(&Part::Package) && has(&Part::Lang) && has(&Part::Name);
+        let by_content =
+            has_hash && has(&Part::Bitrate) && has(&Part::Channels) && has(&Part::SampleRate);
+        if !by_source && !by_content {
+            return Err(format!(
+                "Output name {template} could give different outputs the same name, \
+                 it needs either {{package}}, {{lang}} and {{name}}, \
+                 or {{hash}} of at least {MIN_HASH_LENGTH} characters, {{bitrate}}, {{channels}} and {{samplerate}}"
+            ));
+        }
+        Ok(Template { parts, by_content })
+    }
+
+    /// `{bitrate}kb.{channels}ch.{samplerate}hz.{hash:10}.{ext}`, without `{samplerate}hz.`
+    /// for outputs at the default sample rate.
+    fn default_names() -> Self {
+        Template {
+            parts: vec![
+                Part::Bitrate,
+                Part::Text("kb.".to_string()),
+                Part::Channels,
+                Part::Text("ch.".to_string()),
+                Part::UnlessDefaultSampleRate(vec![
+                    Part::SampleRate,
+                    Part::Text("hz.".to_string()),
+                ]),
+                Part::Hash(DEFAULT_HASH_LENGTH),
+            ],
+            by_content: true,
+        }
+    }
+
+    /// The template set in the config, or the default one.
+    /// Fails with `Unsupported` when the template is invalid.
+    pub fn from_config(config: &Config) -> io::Result<Self> {
+        match config.output_name.as_deref() {
+            Some(template) => {
+                Template::parse(template).map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))
+            }
+            None => Ok(Template::default_names()),
+        }
+    }
+
+    /// Whether an output with the name of a source is up to date even without a cache
+    /// entry, otherwise it may still hold the audio of another source or other settings.
+    pub fn names_content(&self) -> bool {
+        self.by_content
+    }
+
+    /// The path of the output relative to outdir, without the extension.
+    pub fn stem(&self, values: &Values) -> String {
+        render(&self.parts, values)
+    }
+}
+
+fn render(parts: &[Part], values: &Values) -> String {
+    let mut stem = String::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => stem.push_str(text),
+            Part::Package => stem.push_str(values.package),
+            Part::Lang => stem.push_str(values.lang),
+            Part::Name => stem.push_str(values.name),
+            Part::Bitrate => stem.push_str(&values.bitrate.to_string()),
+            Part::Channels => stem.push_str(&values.channels.to_string()),
+            Part::SampleRate => stem.push_str(&values.sample_rate.to_string()),
+            Part::Hash(length) => {
+                stem.push_str(&values.hash[..(*length).min(values.hash.len())]);
+            }
+            Part::UnlessDefaultSampleRate(parts) => {
+                if values.sample_rate != crate::DEFAULT_SAMPLE_RATE {
+                    stem.push_str(&render(parts, values));
+                }
+            }
+        }
+    }
+    stem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values() -> Values<'static> {
+        Values {
+            package: "ui",
+            lang: "en",
+            name: "click",
+            bitrate: 96,
+            channels: 2,
+            sample_rate: 48000,
+            hash: "0123456789abcdef",
+        }
+    }
+
+    #[test]
+    fn default_matches_the_old_names() {
+        let template = Template::default_names();
+        assert_eq!(template.stem(&values()), "96kb.2ch.0123456789");
+        let resampled = Values {
+            sample_rate: 24000,
+            ..values()
+        };
+        assert_eq!(template.stem(&resampled), "96kb.2ch.24000hz.0123456789");
+    }
+
+    #[test]
+    fn renders_directories() {
+        let template = Template::parse("{package}/{lang}/{name}.{ext}").unwrap();
+        assert_eq!(template.stem(&values()), "ui/en/click");
+        let sharded =
+            Template::parse("{hash:2}/{bitrate}.{channels}.{samplerate}.{hash:16}.{ext}").unwrap();
+        assert_eq!(sharded.stem(&values()), "01/96.2.48000.0123456789abcdef");
+    }
+
+    #[test]
+    fn rejects_templates_that_could_collide() {
+        assert!(Template::parse("{package}/{name}.{ext}").is_err());
+        assert!(Template::parse("{hash}.{bitrate}.{ext}").is_err());
+        assert!(Template::parse("{hash:4}.{bitrate}.{channels}.{samplerate}.{ext}").is_err());
+        assert!(Template::parse("{package}/{lang}/{name}").is_err());
+        assert!(Template::parse("../{package}/{lang}/{name}.{ext}").is_err());
+        assert!(Template::parse("{package}/{lang}/{name}.{size}.{ext}").is_err());
+    }
+}
@@ -1,7 +1,6 @@
 use crate::logging::LogLevel;
 
-
-#[allow(clippy::struct_excessive_bools)]
+#[allow(clippy::struct_excessive_bools, dead_code)]
 #[derive(Debug)]
 pub struct ParsedArgs {
     pub indir: String,
@@ -0,0 +1,304 @@
+// What a run would do, without doing it.
+// Built from the same items and sprites a real run creates, compared against
+// the cache of the previous run to explain why each output would be encoded.
+
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{encoder, format::Format, info, logging, sprite::Sprite};
+
+/// Name of the atlas in the output directory, never reported as orphaned.
+const ATLAS_FILE: &str = ".atlas.json";
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The source was not part of the previous run.
+    New,
+    /// The content of the source changed since the previous run.
+    Changed,
+    /// The source and its settings are unchanged but the output is gone.
+    MissingOutput,
+    /// The source is unchanged but its settings resolve to a different output.
+    ConfigChange,
+}
+
+impl Reason {
+    fn describe(self) -> &'static str {
+        match self {
+            Reason::New => "new",
+            Reason::Changed => "changed",
+            Reason::MissingOutput => "missing output",
+            Reason::ConfigChange => "config change",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Output {
+    pub path: String,
+    pub format: Format,
+    pub estimated_size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+    /// Source file, or the package and language for a sprite.
+    pub source: String,
+    pub package: String,
+    pub lang: String,
+    pub reason: Reason,
+    pub outputs: Vec<Output>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub encode: Vec<Entry>,
+    pub sprites: Vec<Entry>,
+    /// Files in the output directory nothing refers to anymore.
+    /// `None` when only some packages are selected or some sources were skipped,
+    /// since the outputs of the others would look orphaned.
+    pub orphaned: Option<Vec<String>>,
+    pub estimated_size: u64,
+}
+
+fn reason_for(item: &info::Item, format: Format, previous: &info::Map) -> Reason {
+    match previous.get_item(item) {
+        None => Reason::New,
+        Some(cached) if cached.hash != item.hash => Reason::Changed,
+        Some(cached)
+            if cached.outfile != item.outfile
+                || cached.fingerprint != item.fingerprint
+                || !cached.formats.contains(&format) =>
+        {
+            Reason::ConfigChange
+        }
+        Some(_) => Reason::MissingOutput,
+    }
+}
+
+impl Plan {
+    pub fn new(
+        outdir: &str,
+        items: &[info::Item],
+        sprites: &[Sprite],
+        previous: &info::Map,
+        partial: bool,
+    ) -> io::Result<Self> {
+        let members = crate::sprite::members(sprites);
+        let mut encode: Vec<Entry> = Vec::new();
+        for item in items {
+            if members.contains(item) {
+                continue;
+            }
+            let missing: Vec<Format> = item
+                .formats
+                .iter()
+                .filter(|format| item.outdated || !item.output_path_for(**format).exists())
+                .copied()
+                .collect();
+            let Some(first) = missing.first() else {
+                continue;
+            };
+            encode.push(Entry {
+                source: item.path.clone(),
+                package: item.package.clone(),
+                lang: item.lang.clone(),
+                reason: reason_for(item, *first, previous),
+                outputs: missing
+                    .iter()
+                    .map(|format| Output {
+                        path: item.output_path_for(*format).to_string_lossy().into_owned(),
+                        format: *format,
+                        estimated_size: format.estimated_size(
+                            item.bitrate,
+                            item.target_channels,
+                            item.target_sample_rate,
+                            item.num_samples,
+                        ),
+                    })
+                    .collect(),
+            });
+        }
+        encode.sort_by(|a, b| a.source.cmp(&b.source));
+
+        let mut sprite_entries: Vec<Entry> = Vec::new();
+        for sprite in sprites {
+            let outputs: Vec<Output> = sprite
+                .formats
+                .iter()
+                .filter(|format| !sprite.output_path_for(**format).exists())
+                .map(|format| Output {
+                    path: sprite
+                        .output_path_for(*format)
+                        .to_string_lossy()
+                        .into_owned(),
+                    format: *format,
+                    estimated_size: format.estimated_size(
+                        sprite.bitrate,
+                        sprite.target_channels,
+                        sprite.target_sample_rate,
+                        sprite.num_samples,
+                    ),
+                })
+                .collect();
+            if outputs.is_empty() {
+                continue;
+            }
+            // sprites are not cached, so any of their members changing shows up as a new sprite
+            let reason = if sprite.members.iter().all(|member| {
+                previous.get(&member.path).is_some()
+                    || previous
+                        .get_inherited(&member.path, &sprite.package)
+                        .is_some()
+            }) {
+                Reason::MissingOutput
+            } else {
+                Reason::New
+            };
+            sprite_entries.push(Entry {
+                source: format!("sprite of {} ({})", sprite.package, sprite.lang),
+                package: sprite.package.clone(),
+                lang: sprite.lang.clone(),
+                reason,
+                outputs,
+            });
+        }
+
+        let orphaned = if partial {
+            None
+        } else {
+            let orphaned = orphans(outdir, items, sprites)?;
+            Some(
+                orphaned
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+            )
+        };
+
+        let estimated_size = encode
+            .iter()
+            .chain(sprite_entries.iter())
+            .flat_map(|entry| entry.outputs.iter())
+            .map(|output| output.estimated_size)
+            .sum();
+        Ok(Plan {
+            encode,
+            sprites: sprite_entries,
+            orphaned,
+            estimated_size,
+        })
+    }
+
+    /// Logs the plan in a readable form.
+    pub fn print(&self) {
+        for entry in self.encode.iter().chain(self.sprites.iter()) {
+            let formats: Vec<String> = entry
+                .outputs
+                .iter()
+                .map(|output| {
+                    format!(
+                        "{} ~{}",
+                        output.format,
+                        logging::size(output.estimated_size)
+                    )
+                })
+                .collect();
+            info!(
+                "Would encode {} ({}): {}",
+                entry.source,
+                entry.reason.describe(),
+                formats.join(", ")
+            );
+        }
+        for reason in [
+            Reason::New,
+            Reason::Changed,
+            Reason::MissingOutput,
+            Reason::ConfigChange,
+        ] {
+            let count = self
+                .encode
+                .iter()
+                .chain(self.sprites.iter())
+                .filter(|entry| entry.reason == reason)
+                .count();
+            if count > 0 {
+                info!("{count} {}", reason.describe());
+            }
+        }
+        match &self.orphaned {
+            Some(orphaned) => {
+                for path in orphaned {
+                    warn!("Orphaned output: {path}");
+                }
+                info!("{} orphaned outputs", orphaned.len());
+            }
+            None => info!(
+                "Skipping orphaned outputs because only some packages are selected or some sources were skipped"
+            ),
+        }
+        success!(
+            "Would encode {} sounds and {} sprites, about {}",
+            self.encode.len(),
+            self.sprites.len(),
+            logging::size(self.estimated_size)
+        );
+    }
+
+    pub fn save_json(&self, path: &str) -> io::Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| io::Error::other(e.to_string()))
+    }
+}
+
+/// Files in `outdir` that none of the items or sprites encode to, sorted by path.
+/// Sounds that are part of a sprite only refer to the sprite.
+pub fn orphans(outdir: &str, items: &[info::Item], sprites: &[Sprite]) -> io::Result<Vec<PathBuf>> {
+    let members = crate::sprite::members(sprites);
+    let mut referenced: HashSet<PathBuf> = HashSet::new();
+    for item in items.iter().filter(|item| !members.contains(item)) {
+        for format in &item.formats {
+            referenced.insert(item.output_path_for(*format));
+        }
+    }
+    for sprite in sprites {
+        for format in &sprite.formats {
+            referenced.insert(sprite.output_path_for(*format));
+        }
+    }
+    let outdir = Path::new(outdir).canonicalize()?;
+    let mut orphaned: Vec<PathBuf> = Vec::new();
+    find_orphans(&outdir, &outdir, &referenced, &mut orphaned)?;
+    orphaned.sort();
+    Ok(orphaned)
+}
+
+fn find_orphans(
+    dir: &Path,
+    outdir: &Path,
+    referenced: &HashSet<PathBuf>,
+    orphaned: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find_orphans(&path, outdir, referenced, orphaned)?;
+            continue;
+        }
+        if path == outdir.join(ATLAS_FILE) || encoder::is_temp_file(&path) {
+            continue;
+        }
+        if !referenced.contains(&path) {
+            orphaned.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Cursor},
+    path::Path,
+};
+
+use crate::format::Format;
+
+/// Opus always runs at 48kHz internally, the pre-skip is counted at this rate.
+const OPUS_RATE: u64 = 48000;
+/// ffmpeg encodes opus with 20ms frames.
+const OPUS_FRAME_SIZE: u64 = 960;
+
+/// Samples added by the encoder before and after the original audio,
+/// counted at the output sample rate.
+/// A decoder that does not trim these itself should skip `priming` samples
+/// and then keep `num_samples` samples.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Delay {
+    pub priming: u32,
+    pub padding: u32,
+}
+
+/// Reads the encoder delay and padding from an encoded file.
+/// `num_samples` and `sample_rate` describe the original audio at the output rate.
+pub fn delay(
+    path: &Path,
+    format: Format,
+    num_samples: usize,
+    sample_rate: u32,
+) -> io::Result<Delay> {
+    match format {
+        Format::Webm | Format::Opus => {
+            let buffer = fs::read(path)?;
+            opus_delay(&buffer, num_samples, sample_rate)
+        }
+        Format::Mp4 => {
+            let buffer = fs::read(path)?;
+            mp4_delay(&buffer, num_samples, sample_rate)
+        }
+        // vorbis and flac are trimmed exactly by their containers
+        Format::Flac | Format::Ogg => Ok(Delay::default()),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn to_rate(samples: u64, from_rate: u64, to_rate: u32) -> u32 {
+    if from_rate == 0 {
+        return samples as u32;
+    }
+    ((samples * u64::from(to_rate) + from_rate / 2) / from_rate) as u32
+}
+
+/// The `OpusHead` identification header is stored as is in both the ogg page
+/// and the matroska codec private data, so finding it works for both containers.
+fn opus_delay(buffer: &[u8], num_samples: usize, sample_rate: u32) -> io::Result<Delay> {
+    let head = find(buffer, b"OpusHead").ok_or_else(|| invalid("OpusHead not found"))?;
+    let mut cursor = Cursor::new(&buffer[head..]);
+    cursor.set_position(10);
+    let pre_skip = u64::from(cursor.read_u16::<LittleEndian>()?);
+    let length = (num_samples as u64 * OPUS_RATE).div_ceil(u64::from(sample_rate.max(1)));
+    let decoded = (pre_skip + length).div_ceil(OPUS_FRAME_SIZE) * OPUS_FRAME_SIZE;
+    Ok(Delay {
+        priming: to_rate(pre_skip, OPUS_RATE, sample_rate),
+        padding: to_rate(decoded - pre_skip - length, OPUS_RATE, sample_rate),
+    })
+}
+
+/// Returns the payload of the first box with the given type.
+fn find_box<'a>(buffer: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut offset = 0;
+    while offset + 8 <= buffer.len() {
+        let mut cursor = Cursor::new(&buffer[offset..]);
+        let size = cursor.read_u32::<BigEndian>().ok()? as usize;
+        let mut box_kind = [0u8; 4];
+        std::io::Read::read_exact(&mut cursor, &mut box_kind).ok()?;
+        let (header, size) = match size {
+            0 => (8, buffer.len() - offset),
+            1 => (16, cursor.read_u64::<BigEndian>().ok()? as usize),
+            size => (8, size),
+        };
+        if size < header || offset + size > buffer.len() {
+            return None;
+        }
+        if &box_kind == kind {
+            return Some(&buffer[offset + header..offset + size]);
+        }
+        offset += size;
+    }
+    None
+}
+
+fn find_path<'a>(buffer: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter()
+        .try_fold(buffer, |payload, kind| find_box(payload, kind))
+}
+
+/// Reads the media timescale and duration from an `mdhd` box.
+fn media_header(mdhd: &[u8]) -> io::Result<(u64, u64)> {
+    let mut cursor = Cursor::new(mdhd);
+    let version = cursor.read_u8()?;
+    cursor.set_position(4);
+    if version == 1 {
+        cursor.set_position(cursor.position() + 16);
+        let timescale = u64::from(cursor.read_u32::<BigEndian>()?);
+        Ok((timescale, cursor.read_u64::<BigEndian>()?))
+    } else {
+        cursor.set_position(cursor.position() + 8);
+        let timescale = u64::from(cursor.read_u32::<BigEndian>()?);
+        Ok((timescale, u64::from(cursor.read_u32::<BigEndian>()?)))
+    }
+}
+
+/// Reads the media time of the first edit list entry, which is where playback starts.
+fn edit_list_media_time(elst: &[u8]) -> io::Result<u64> {
+    let mut cursor = Cursor::new(elst);
+    let version = cursor.read_u8()?;
+    cursor.set_position(4);
+    if cursor.read_u32::<BigEndian>()? == 0 {
+        return Err(invalid("Edit list is empty"));
+    }
+    if version == 1 {
+        let _segment_duration = cursor.read_u64::<BigEndian>()?;
+        Ok(cursor.read_i64::<BigEndian>()?.max(0) as u64)
+    } else {
+        let _segment_duration = cursor.read_u32::<BigEndian>()?;
+        Ok(i64::from(cursor.read_i32::<BigEndian>()?).max(0) as u64)
+    }
+}
+
+/// Reads the iTunes gapless info, stored as hex numbers in the form
+/// ` 00000000 <priming> <padding> <length> ...`.
+fn itunsmpb(buffer: &[u8]) -> Option<(u64, u64)> {
+    let name = find(buffer, b"iTunSMPB")?;
+    let rest = &buffer[name..];
+    let data = find(rest, b"data")?;
+    // skip the data type and locale fields
+    let text = String::from_utf8_lossy(rest.get(data + 12..)?.split(|b| *b == 0).next()?);
+    let mut fields = text.split_whitespace().skip(1);
+    let priming = u64::from_str_radix(fields.next()?, 16).ok()?;
+    let padding = u64::from_str_radix(fields.next()?, 16).ok()?;
+    Some((priming, padding))
+}
+
+fn mp4_delay(buffer: &[u8], num_samples: usize, sample_rate: u32) -> io::Result<Delay> {
+    let moov = find_box(buffer, b"moov").ok_or_else(|| invalid("moov box not found"))?;
+    let mdhd = find_path(moov, &[b"trak", b"mdia", b"mdhd"]);
+    let elst = find_path(moov, &[b"trak", b"edts", b"elst"]);
+    if let (Some(mdhd), Some(elst)) = (mdhd, elst) {
+        // the segment duration of the edit list is rounded to the movie timescale,
+        // so the padding is derived from the known length instead
+        let (media_timescale, media_duration) = media_header(mdhd)?;
+        let media_time = edit_list_media_time(elst)?;
+        let length = num_samples as u64 * media_timescale / u64::from(sample_rate.max(1));
+        let padding = media_duration.saturating_sub(media_time + length);
+        return Ok(Delay {
+            priming: to_rate(media_time, media_timescale, sample_rate),
+            padding: to_rate(padding, media_timescale, sample_rate),
+        });
+    }
+    if let Some((priming, padding)) = itunsmpb(moov) {
+        return Ok(Delay {
+            priming: priming as u32,
+            padding: padding as u32,
+        });
+    }
+    Err(invalid("No edit list or iTunSMPB found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_opus_pre_skip() {
+        let mut buffer = b"OggS....OpusHead".to_vec();
+        // version, channels, pre-skip 312
+        buffer.extend_from_slice(&[1, 1, 0x38, 0x01, 0x80, 0xBB, 0, 0, 0, 0, 0]);
+        let delay = opus_delay(&buffer, 48000, 48000).unwrap();
+        assert_eq!(delay.priming, 312);
+        assert_eq!((312 + 48000 + delay.padding) % 960, 0);
+    }
+
+    #[test]
+    fn reads_itunsmpb() {
+        let mut buffer = b"----....meanmean....nameiTunSMPB....data".to_vec();
+        buffer.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+        buffer.extend_from_slice(b" 00000000 00000840 000001CA 00000000000D4E6F\0");
+        assert_eq!(itunsmpb(&buffer), Some((0x840, 0x1CA)));
+    }
+}
@@ -0,0 +1,229 @@
+// Finds the `sources` settings of a sound.
+// Keys are file names without the extension, globs like `voice_*`, or regexes
+// starting with `re:`. Every entry that matches a name applies, for each setting
+// the exact entry wins over patterns, and a pattern with more literal characters
+// wins over one with fewer.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::config::Source;
+
+const REGEX_PREFIX: &str = "re:";
+
+enum Matcher {
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+struct Pattern {
+    key: String,
+    matcher: Matcher,
+    /// Number of literal characters, patterns with more are applied first.
+    specificity: usize,
+    source: Source,
+    matched: AtomicBool,
+}
+
+impl Pattern {
+    fn matches(&self, name: &str) -> bool {
+        match self.matcher {
+            Matcher::Glob(ref pattern) => pattern.matches(name),
+            Matcher::Regex(ref regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// The `sources` of a package, ready to be matched against its sound names.
+pub struct Sources {
+    exact: HashMap<String, Source>,
+    /// Most specific first, ties in key order.
+    patterns: Vec<Pattern>,
+}
+
+fn is_glob(key: &str) -> bool {
+    key.contains(['*', '?', '['])
+}
+
+/// Whether `key` is a glob or regex instead of a name.
+pub fn is_pattern(key: &str) -> bool {
+    key.starts_with(REGEX_PREFIX) || is_glob(key)
+}
+
+fn glob_specificity(pattern: &str) -> usize {
+    let mut count = 0;
+    let mut in_class = false;
+    for c in pattern.chars() {
+        match c {
+            '[' if !in_class => in_class = true,
+            // a class matches a single character, so it counts as one
+            ']' if in_class => {
+                in_class = false;
+                count += 1;
+            }
+            '*' | '?' if !in_class => {}
+            _ if !in_class => count += 1,
+            _ => {}
+        }
+    }
+    count
+}
+
+fn regex_specificity(pattern: &str) -> usize {
+    let mut count = 0;
+    let mut escaped = false;
+    let mut in_class = false;
+    for c in pattern.chars() {
+        if escaped {
+            // `\.` is a literal dot, `\d` a class
+            if !in_class && !c.is_ascii_alphanumeric() {
+                count += 1;
+            }
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if in_class {
+            // a class matches a single character, so it counts as one
+            if c == ']' {
+                in_class = false;
+                count += 1;
+            }
+        } else if c == '[' {
+            in_class = true;
+        } else if c.is_alphanumeric() || c == '_' || c == '-' {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// `source` with the settings it does not have taken from `fallback`.
+fn layer(source: Source, fallback: &Source) -> Source {
+    Source {
+        bitrate: source.bitrate.or(fallback.bitrate),
+        channels: source.channels.or(fallback.channels),
+        formats: source.formats.or_else(|| fallback.formats.clone()),
+        samplerate: source.samplerate.or(fallback.samplerate),
+        downmix: source.downmix.or_else(|| fallback.downmix.clone()),
+    }
+}
+
+impl Sources {
+    /// Fails with `Unsupported` when a key is not a valid glob or regex.
+    pub fn new(package: &str, sources: Option<&HashMap<String, Source>>) -> io::Result<Self> {
+        let mut exact: HashMap<String, Source> = HashMap::new();
+        let mut patterns: Vec<Pattern> = Vec::new();
+        for (key, source) in sources.into_iter().flatten() {
+            let invalid = |e: String| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Invalid pattern {key} in the sources of package {package}: {e}"),
+                )
+            };
+            let (matcher, specificity) = if let Some(regex) = key.strip_prefix(REGEX_PREFIX) {
+                // the whole name has to match, like with globs
+                let compiled = regex::Regex::new(&format!("^(?:{regex})$"))
+                    .map_err(|e| invalid(e.to_string()))?;
+                (Matcher::Regex(compiled), regex_specificity(regex))
+            } else if is_glob(key) {
+                let compiled = glob::Pattern::new(key).map_err(|e| invalid(e.to_string()))?;
+                (Matcher::Glob(compiled), glob_specificity(key))
+            } else {
+                exact.insert(key.clone(), source.clone());
+                continue;
+            };
+            patterns.push(Pattern {
+                key: key.clone(),
+                matcher,
+                specificity,
+                source: source.clone(),
+                matched: AtomicBool::new(false),
+            });
+        }
+        patterns.sort_by(|a, b| {
+            b.specificity
+                .cmp(&a.specificity)
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        Ok(Sources { exact, patterns })
+    }
+
+    /// The settings of the sound called `name`, `None` when no entry matches it.
+    pub fn get(&self, name: &str) -> Option<Source> {
+        let mut resolved = self.exact.get(name).cloned();
+        for pattern in self.patterns.iter().filter(|pattern| pattern.matches(name)) {
+            pattern.matched.store(true, Ordering::Relaxed);
+            resolved = Some(match resolved {
+                Some(source) => layer(source, &pattern.source),
+                None => pattern.source.clone(),
+            });
+        }
+        resolved
+    }
+
+    /// Patterns no sound has matched so far, sorted.
+    pub fn unmatched(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self
+            .patterns
+            .iter()
+            .filter(|pattern| !pattern.matched.load(Ordering::Relaxed))
+            .map(|pattern| pattern.key.as_str())
+            .collect();
+        keys.sort();
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(bitrate: Option<u32>, channels: Option<u16>) -> Source {
+        Source {
+            bitrate,
+            channels,
+            formats: None,
+            samplerate: None,
+            downmix: None,
+        }
+    }
+
+    #[test]
+    fn exact_wins_over_specific_patterns() {
+        let map = HashMap::from([
+            ("voice_*".to_string(), source(Some(32), Some(1))),
+            ("voice_b*".to_string(), source(Some(40), None)),
+            (
+                "re:voice_(banker|player)".to_string(),
+                source(Some(48), None),
+            ),
+            ("voice_banker".to_string(), source(Some(24), None)),
+            ("music_*".to_string(), source(None, Some(2))),
+        ]);
+        let sources = Sources::new("ui", Some(&map)).unwrap();
+        let banker = sources.get("voice_banker").unwrap();
+        assert_eq!((banker.bitrate, banker.channels), (Some(24), Some(1)));
+        let bonus = sources.get("voice_bonus").unwrap();
+        assert_eq!((bonus.bitrate, bonus.channels), (Some(40), Some(1)));
+        let player = sources.get("voice_player").unwrap();
+        assert_eq!(player.bitrate, Some(48));
+        assert!(sources.get("effect_spin").is_none());
+        assert_eq!(sources.unmatched(), ["music_*"]);
+    }
+
+    #[test]
+    fn classes_count_as_one_character() {
+        assert_eq!(regex_specificity("voice_[a-z]+"), 7);
+        assert_eq!(regex_specificity(r"voice_[\]x]\.b"), 9);
+        assert!(regex_specificity("voice_[a-z]+") < glob_specificity("voice_ba*"));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let map = HashMap::from([("re:voice_(".to_string(), source(None, None))]);
+        assert!(Sources::new("ui", Some(&map)).is_err());
+    }
+}
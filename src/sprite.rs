@@ -0,0 +1,309 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    channels,
+    config::Config,
+    encoder::{self, EncodeOptions},
+    format::Format,
+    info::Item,
+    naming,
+    probe::Delay,
+};
+
+static DEFAULT_MAX_DURATION: f64 = 2.0;
+static DEFAULT_GAP: u32 = 50;
+
+/// A sound placed in a sprite, offset and length are in samples at the sprite sample rate.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub path: String,
+    pub offset: usize,
+    pub length: usize,
+    pub downmix: Option<Vec<Vec<f32>>>,
+}
+
+/// Short sounds of a package concatenated into a single output per format.
+#[derive(Debug, Clone)]
+pub struct Sprite {
+    pub package: String,
+    pub lang: String,
+    pub outfile: String,
+    pub output_path: String,
+    /// sha256 of the members, their settings and positions.
+    pub hash: String,
+    pub bitrate: u32,
+    pub target_channels: u16,
+    pub target_sample_rate: u32,
+    pub formats: Vec<Format>,
+    pub num_samples: usize,
+    pub members: Vec<Member>,
+    pub delays: HashMap<Format, Delay>,
+}
+
+impl Sprite {
+    /// Path of the encoded sprite for the given format.
+    pub fn output_path_for(&self, format: Format) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.output_path, format.extension()))
+    }
+}
+
+/// The items that are encoded as part of a sprite instead of on their own.
+pub struct Members(HashSet<(String, String)>);
+
+impl Members {
+    pub fn contains(&self, item: &Item) -> bool {
+        // an inherited source is a member of the sprites of some packages only
+        self.0.contains(&(item.package.clone(), item.path.clone()))
+    }
+}
+
+pub fn members(sprites: &[Sprite]) -> Members {
+    Members(
+        sprites
+            .iter()
+            .flat_map(|sprite| {
+                sprite
+                    .members
+                    .iter()
+                    .map(|member| (sprite.package.clone(), member.path.clone()))
+            })
+            .collect(),
+    )
+}
+
+/// Groups the short sounds of every package with sprites enabled.
+/// Sounds are only grouped together when they share language and encoding settings,
+/// so a package can end up with more than one sprite.
+pub fn create_sprites(config: &Config, items: &[Item]) -> io::Result<Vec<Sprite>> {
+    type Key = (String, String, u32, u16, u32, Vec<Format>);
+    let mut groups: HashMap<Key, Vec<&Item>> = HashMap::new();
+    for item in items {
+        let Some(settings) = config
+            .packages
+            .get(&item.package)
+            .and_then(|package| package.sprite.as_ref())
+        else {
+            continue;
+        };
+        let duration = item.num_samples as f64 / f64::from(item.target_sample_rate);
+        if duration > settings.max_duration.unwrap_or(DEFAULT_MAX_DURATION) {
+            continue;
+        }
+        let key = (
+            item.package.clone(),
+            item.lang.clone(),
+            item.bitrate,
+            item.target_channels,
+            item.target_sample_rate,
+            item.formats.clone(),
+        );
+        groups.entry(key).or_default().push(item);
+    }
+
+    if groups.is_empty() {
+        return Ok(Vec::new());
+    }
+    let outdir = Path::new(&config.outdir).canonicalize()?;
+    let template = naming::Template::from_config(config)?;
+    let mut sprites: Vec<Sprite> = Vec::new();
+    for ((package, lang, bitrate, target_channels, sample_rate, formats), mut items) in groups {
+        if items.len() < 2 {
+            continue;
+        }
+        items.sort_by(|a, b| a.name.cmp(&b.name));
+        let gap_ms = config.packages[&package]
+            .sprite
+            .as_ref()
+            .and_then(|settings| settings.gap)
+            .unwrap_or(DEFAULT_GAP);
+        // players skip the priming of each format from the atlas, so the sounds
+        // do not have to start on a frame boundary and are just `gap` apart
+        let gap = u64::from(sample_rate) * u64::from(gap_ms) / 1000;
+
+        let mut hasher = Sha256::new();
+        let mut members: Vec<Member> = Vec::with_capacity(items.len());
+        let mut offset: u64 = 0;
+        for item in &items {
+            let length = item.num_samples as u64;
+            hasher.update(item.hash.as_bytes());
+            hasher.update(item.fingerprint.as_bytes());
+            hasher.update(format!("{offset}:{length}:{:?}", item.downmix).as_bytes());
+            members.push(Member {
+                path: item.path.clone(),
+                offset: offset as usize,
+                length: length as usize,
+                downmix: item.downmix.clone(),
+            });
+            offset += length + gap;
+        }
+        let hash = format!("{:x}", hasher.finalize());
+        // the members differ between sprites, so their hash keeps the name unique
+        let outfile = template.stem(&naming::Values {
+            package: &package,
+            lang: &lang,
+            name: &format!("_sprite.{}", &hash[..10]),
+            bitrate,
+            channels: target_channels,
+            sample_rate,
+            hash: &hash,
+        });
+        let output_path = outdir.join(&outfile);
+        sprites.push(Sprite {
+            package,
+            lang,
+            outfile,
+            output_path: output_path.to_string_lossy().into_owned(),
+            hash,
+            bitrate,
+            target_channels,
+            target_sample_rate: sample_rate,
+            formats,
+            num_samples: offset as usize,
+            members,
+            delays: HashMap::new(),
+        });
+    }
+    sprites.sort_by(|a, b| a.outfile.cmp(&b.outfile));
+    Ok(sprites)
+}
+
+/// Estimated peak memory of encoding a sprite, which is held in memory as float samples.
+pub fn estimate_memory(sprite: &Sprite) -> u64 {
+    let samples = sprite.num_samples as u64 * u64::from(sprite.target_channels);
+    let size: u64 = sprite
+        .members
+        .iter()
+        .map(|member| encoder::file_size(Path::new(&member.path)))
+        .sum();
+    encoder::estimate_memory_for_size(size) + samples * 4
+}
+
+/// Decodes a member to interleaved float samples in the sprite layout.
+fn decode(options: &EncodeOptions, sprite: &Sprite, member: &Member) -> io::Result<Vec<f32>> {
+    let mut command = Command::new(&options.ffmpeg);
+    command.arg("-i").arg(&member.path);
+    if let Some(ref matrix) = member.downmix {
+        command.arg("-af").arg(channels::pan_filter(matrix));
+    }
+    command
+        .arg("-ac")
+        .arg(sprite.target_channels.to_string())
+        .arg("-ar")
+        .arg(sprite.target_sample_rate.to_string())
+        .arg("-f")
+        .arg("f32le")
+        .arg("-");
+    let duration = member.length as f64 / f64::from(sprite.target_sample_rate.max(1));
+    let output = options.run(&mut command, duration)?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "ffmpeg failed to decode {} for sprite {} with status {}",
+            member.path, sprite.outfile, output.status
+        )));
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect())
+}
+
+/// Writes interleaved float samples as a 32 bit float wav file.
+fn write_wav(path: &Path, samples: &[f32], channels: u16, sample_rate: u32) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let data_size = (samples.len() * 4) as u32;
+    writer.write_all(b"RIFF")?;
+    writer.write_u32::<LittleEndian>(36 + data_size)?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_u32::<LittleEndian>(16)?;
+    writer.write_u16::<LittleEndian>(3)?; // ieee float
+    writer.write_u16::<LittleEndian>(channels)?;
+    writer.write_u32::<LittleEndian>(sample_rate)?;
+    writer.write_u32::<LittleEndian>(sample_rate * u32::from(channels) * 4)?;
+    writer.write_u16::<LittleEndian>(channels * 4)?;
+    writer.write_u16::<LittleEndian>(32)?;
+    writer.write_all(b"data")?;
+    writer.write_u32::<LittleEndian>(data_size)?;
+    for sample in samples {
+        writer.write_f32::<LittleEndian>(*sample)?;
+    }
+    writer.flush()
+}
+
+/// Concatenates the members with silence in between and encodes the missing formats.
+pub fn encode(options: &EncodeOptions, sprite: &Sprite) -> io::Result<()> {
+    let missing: Vec<Format> = sprite
+        .formats
+        .iter()
+        .filter(|format| !sprite.output_path_for(**format).exists())
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    debug!(
+        "Encoding sprite {} with {} sounds from {} ({})",
+        sprite.outfile,
+        sprite.members.len(),
+        sprite.package,
+        sprite.lang
+    );
+
+    let channels = usize::from(sprite.target_channels);
+    let mut samples = vec![0.0_f32; sprite.num_samples * channels];
+    for member in &sprite.members {
+        let decoded = decode(options, sprite, member)?;
+        let start = member.offset * channels;
+        let count = (member.length * channels).min(decoded.len());
+        samples[start..start + count].copy_from_slice(&decoded[..count]);
+    }
+    let wav_path = encoder::temp_path(Path::new(&format!("{}.wav", sprite.output_path)));
+    if let Some(parent) = wav_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_wav(
+        &wav_path,
+        &samples,
+        sprite.target_channels,
+        sprite.target_sample_rate,
+    )?;
+
+    let bitrate = sprite.bitrate * u32::from(sprite.target_channels);
+    let duration = sprite.num_samples as f64 / f64::from(sprite.target_sample_rate.max(1));
+    let result = missing.iter().try_for_each(|format| {
+        let outfile = sprite.output_path_for(*format);
+        let mut command = Command::new(&options.ffmpeg);
+        command
+            .arg("-i")
+            .arg(&wav_path)
+            .arg("-b:a")
+            .arg(bitrate.to_string() + "k")
+            .arg("-ar")
+            .arg(sprite.target_sample_rate.to_string())
+            .arg("-map_metadata")
+            .arg("-1")
+            .arg("-y")
+            .args(options.thread_args())
+            .args(format.codec_args(sprite.target_channels, options.ogg_quality));
+        let output = options.run_to_file(&mut command, *format, &outfile, duration)?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "ffmpeg execution failed when encoding {format} sprite {} with status {}",
+                outfile.to_string_lossy(),
+                output.status
+            )))
+        }
+    });
+    let _ = fs::remove_file(&wav_path);
+    result
+}
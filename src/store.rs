@@ -0,0 +1,379 @@
+// Shared store of encoded outputs, so a team and its CI only encode every sound once.
+// Outputs are stored under a key made from the content hash and every setting that changes
+// the encoded bytes, next to their sha256 which is checked after every download.
+// The store is a directory, possibly on a network mount, or a bucket on an S3 compatible
+// endpoint, which is reached through curl.
+
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::{config::Config, encoder, format::Format, info::Item, sprite::Sprite};
+
+/// Part of every key, bump it when the encoder arguments change so old outputs are not reused.
+const STORE_VERSION: &str = "v1";
+const CHECKSUM_SUFFIX: &str = ".sha256";
+const DEFAULT_REGION: &str = "us-east-1";
+const CONNECT_TIMEOUT: u64 = 10;
+const REQUEST_TIMEOUT: u64 = 300;
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+struct S3 {
+    curl: String,
+    /// Url of the bucket and prefix, without a trailing slash.
+    base: String,
+    region: String,
+    /// Requests are unsigned without credentials, which works for public buckets.
+    credentials: Option<Credentials>,
+}
+
+enum Backend {
+    Dir(PathBuf),
+    S3(S3),
+}
+
+pub struct Store {
+    backend: Backend,
+    /// Whether outputs encoded by this run are uploaded.
+    pub publish: bool,
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Key of an encoded sound, from its content and the settings it is encoded with.
+pub fn item_key(item: &Item, format: Format, ogg_quality: Option<f32>) -> String {
+    let quality = ogg_quality.filter(|_| format == Format::Ogg);
+    sha256_hex(
+        format!(
+            "{STORE_VERSION}:{}:{format}:{}:{}:{}:{}:{:?}:{quality:?}",
+            item.hash,
+            item.bitrate,
+            item.input_channels,
+            item.target_channels,
+            item.target_sample_rate,
+            item.downmix,
+        )
+        .as_bytes(),
+    )
+}
+
+/// Key of an encoded sprite, its hash already covers the members and their positions.
+pub fn sprite_key(sprite: &Sprite, format: Format, ogg_quality: Option<f32>) -> String {
+    let quality = ogg_quality.filter(|_| format == Format::Ogg);
+    sha256_hex(
+        format!(
+            "{STORE_VERSION}:sprite:{}:{format}:{}:{}:{}:{quality:?}",
+            sprite.hash, sprite.bitrate, sprite.target_channels, sprite.target_sample_rate,
+        )
+        .as_bytes(),
+    )
+}
+
+/// Name of an output in the store, sharded by the start of the key.
+fn object_name(key: &str, format: Format) -> String {
+    format!("{STORE_VERSION}/{}/{key}.{}", &key[..2], format.extension())
+}
+
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(CHECKSUM_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Quotes a value for a curl config file.
+fn curl_quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl S3 {
+    /// Runs curl and returns the http status.
+    /// Credentials are passed on stdin so they do not show up in the process list.
+    fn request(&self, object: &str, args: &[OsString]) -> io::Result<u16> {
+        let url = format!("{}/{object}", self.base);
+        let mut command = Command::new(&self.curl);
+        command
+            .arg("--silent")
+            .arg("--show-error")
+            .arg("--connect-timeout")
+            .arg(CONNECT_TIMEOUT.to_string())
+            .arg("--max-time")
+            .arg(REQUEST_TIMEOUT.to_string())
+            .arg("--write-out")
+            .arg("%{http_code}")
+            .args(args);
+        let mut config = String::new();
+        if let Some(ref credentials) = self.credentials {
+            command
+                .arg("--aws-sigv4")
+                .arg(format!("aws:amz:{}:s3", self.region))
+                .arg("--header")
+                .arg("x-amz-content-sha256: UNSIGNED-PAYLOAD")
+                .arg("--config")
+                .arg("-");
+            let user = format!("{}:{}", credentials.access_key, credentials.secret_key);
+            config.push_str(&format!("user = {}\n", curl_quote(&user)));
+            if let Some(ref token) = credentials.session_token {
+                let header = format!("x-amz-security-token: {token}");
+                config.push_str(&format!("header = {}\n", curl_quote(&header)));
+            }
+        }
+        command
+            .arg(&url)
+            .stdin(if config.is_empty() {
+                Stdio::null()
+            } else {
+                Stdio::piped()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = command
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not run {}: {e}", self.curl)))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(config.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "curl failed for {url}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::other(format!("curl gave no status for {url}")))
+    }
+}
+
+impl Backend {
+    /// Copies an object to `dest`, returns false when the store does not have it.
+    fn get(&self, object: &str, dest: &Path) -> io::Result<bool> {
+        match self {
+            Backend::Dir(root) => match fs::copy(root.join(object), dest) {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            },
+            Backend::S3(s3) => {
+                let status = s3.request(object, &["--output".into(), dest.into()])?;
+                match status {
+                    200 => Ok(true),
+                    // S3 answers 403 for missing objects when listing is not allowed
+                    403 | 404 => {
+                        let _ = fs::remove_file(dest);
+                        Ok(false)
+                    }
+                    status => {
+                        let _ = fs::remove_file(dest);
+                        Err(io::Error::other(format!(
+                            "Downloading {object} failed with status {status}"
+                        )))
+                    }
+                }
+            }
+        }
+    }
+
+    fn put(&self, source: &Path, object: &str) -> io::Result<()> {
+        match self {
+            Backend::Dir(root) => {
+                let dest = root.join(object);
+                if let Some(parent) = dest.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // other machines may read or publish the same object while this one writes it
+                let temp = encoder::unique_temp_path(&dest);
+                fs::copy(source, &temp)?;
+                fs::rename(&temp, &dest).inspect_err(|_| {
+                    let _ = fs::remove_file(&temp);
+                })
+            }
+            Backend::S3(s3) => {
+                let status = s3.request(object, &["--upload-file".into(), source.into()])?;
+                if status == 200 {
+                    Ok(())
+                } else {
+                    Err(io::Error::other(format!(
+                        "Uploading {object} failed with status {status}"
+                    )))
+                }
+            }
+        }
+    }
+
+    fn contains(&self, object: &str) -> bool {
+        match self {
+            Backend::Dir(root) => root.join(object).is_file(),
+            // a HEAD request costs about as much as uploading a small sound
+            Backend::S3(_) => false,
+        }
+    }
+}
+
+impl Store {
+    /// The store set in the config, `None` when there is none.
+    pub fn from_config(config: &Config) -> Option<Store> {
+        let settings = config.store.as_ref()?;
+        let location = settings.location.as_ref()?;
+        let backend = if let Some(bucket) = location.strip_prefix("s3://") {
+            let region = settings.region.clone().unwrap_or_else(default_region);
+            Backend::S3(S3 {
+                curl: settings.curl.clone().unwrap_or("curl".to_string()),
+                base: format!(
+                    "https://s3.{region}.amazonaws.com/{}",
+                    bucket.trim_end_matches('/')
+                ),
+                region,
+                credentials: credentials(),
+            })
+        } else if location.starts_with("http://") || location.starts_with("https://") {
+            Backend::S3(S3 {
+                curl: settings.curl.clone().unwrap_or("curl".to_string()),
+                base: location.trim_end_matches('/').to_string(),
+                region: settings.region.clone().unwrap_or_else(default_region),
+                credentials: credentials(),
+            })
+        } else {
+            Backend::Dir(PathBuf::from(location))
+        };
+        Some(Store {
+            backend,
+            publish: settings.publish.unwrap_or(true),
+        })
+    }
+
+    pub fn describe(&self) -> String {
+        match self.backend {
+            Backend::Dir(ref root) => root.to_string_lossy().into_owned(),
+            Backend::S3(ref s3) => s3.base.clone(),
+        }
+    }
+
+    /// Downloads the output with `key` to `path`.
+    /// Returns false when the store does not have it, and fails when its checksum does not match.
+    pub fn fetch(&self, key: &str, format: Format, path: &Path) -> io::Result<bool> {
+        let object = object_name(key, format);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temp = encoder::temp_path(path);
+        let temp_checksum = encoder::temp_path(&checksum_path(path));
+        // the checksum is published last, so an output without one is incomplete
+        let found = self
+            .backend
+            .get(&(object.clone() + CHECKSUM_SUFFIX), &temp_checksum)?;
+        if !found {
+            return Ok(false);
+        }
+        let expected = fs::read_to_string(&temp_checksum);
+        let _ = fs::remove_file(&temp_checksum);
+        let expected = expected?;
+        if !self.backend.get(&object, &temp)? {
+            return Ok(false);
+        }
+        let actual = sha256_file(&temp)?;
+        if actual != expected.trim() {
+            let _ = fs::remove_file(&temp);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Checksum of {object} in the store does not match"),
+            ));
+        }
+        fs::rename(&temp, path)?;
+        Ok(true)
+    }
+
+    /// Uploads the output at `path` under `key`, followed by its checksum.
+    pub fn publish(&self, key: &str, format: Format, path: &Path) -> io::Result<()> {
+        let object = object_name(key, format);
+        let checksum_object = object.clone() + CHECKSUM_SUFFIX;
+        if self.backend.contains(&checksum_object) {
+            return Ok(());
+        }
+        let checksum = sha256_file(path)?;
+        let temp_checksum = encoder::temp_path(&checksum_path(path));
+        fs::write(&temp_checksum, &checksum)?;
+        let result = self
+            .backend
+            .put(path, &object)
+            .and_then(|()| self.backend.put(&temp_checksum, &checksum_object));
+        let _ = fs::remove_file(&temp_checksum);
+        result
+    }
+}
+
+fn default_region() -> String {
+    env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or(DEFAULT_REGION.to_string())
+}
+
+fn credentials() -> Option<Credentials> {
+    Some(Credentials {
+        access_key: env::var("AWS_ACCESS_KEY_ID").ok()?,
+        secret_key: env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+        session_token: env::var("AWS_SESSION_TOKEN").ok(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fetches_what_was_published() {
+        let dir = env::temp_dir().join(format!("scode-store-{}", std::process::id()));
+        let output = dir.join("out").join("a.webm");
+        fs::create_dir_all(output.parent().unwrap()).unwrap();
+        fs::write(&output, b"encoded").unwrap();
+        let store = Store {
+            backend: Backend::Dir(dir.join("store")),
+            publish: true,
+        };
+        let key = sha256_hex(b"a");
+        store.publish(&key, Format::Webm, &output).unwrap();
+
+        let fetched = dir.join("fetched").join("a.webm");
+        assert!(store.fetch(&key, Format::Webm, &fetched).unwrap());
+        assert_eq!(fs::read(&fetched).unwrap(), b"encoded");
+        assert!(!store
+            .fetch(&sha256_hex(b"b"), Format::Webm, &fetched)
+            .unwrap());
+
+        fs::write(
+            dir.join("store").join(object_name(&key, Format::Webm)),
+            b"bad",
+        )
+        .unwrap();
+        assert!(store.fetch(&key, Format::Webm, &fetched).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+// Checks encoded outputs against the settings they were encoded with.
+// Stream properties are read with ffprobe, silence is detected with the
+// volumedetect filter of ffmpeg.
+
+use serde::Deserialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{encoder::EncodeOptions, format::Format};
+
+/// Allowed difference between the duration of an output and its source in milliseconds.
+pub const DEFAULT_TOLERANCE: u32 = 100;
+/// Files whose loudest sample is below this are considered silent.
+const SILENCE_THRESHOLD_DB: f64 = -90.0;
+
+/// What an output is expected to contain.
+pub struct Expected {
+    pub format: Format,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub num_samples: usize,
+    /// Files the output was encoded from, a silent output is only wrong when one of them is audible.
+    pub sources: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+struct Probe {
+    #[serde(default)]
+    streams: Vec<Stream>,
+    format: Option<Container>,
+}
+
+#[derive(Deserialize)]
+struct Stream {
+    codec_name: Option<String>,
+    channels: Option<u16>,
+    sample_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Container {
+    duration: Option<String>,
+}
+
+/// Probes an output and returns every way it differs from what was expected.
+/// An empty list means the output is fine.
+/// `tolerance` is the allowed difference in duration, in milliseconds.
+pub fn check(
+    options: &EncodeOptions,
+    path: &Path,
+    expected: &Expected,
+    tolerance: u32,
+) -> io::Result<Vec<String>> {
+    let duration = expected.num_samples as f64 / f64::from(expected.sample_rate.max(1));
+    let mut problems: Vec<String> = Vec::new();
+
+    let mut command = Command::new(&options.ffprobe);
+    command
+        .arg("-v")
+        .arg("error")
+        .arg("-select_streams")
+        .arg("a:0")
+        .arg("-show_entries")
+        .arg("stream=codec_name,channels,sample_rate:format=duration")
+        .arg("-of")
+        .arg("json")
+        .arg(path);
+    let output = options.run(&mut command, duration)?;
+    if !output.status.success() {
+        problems.push(format!(
+            "ffprobe failed with status {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+        return Ok(problems);
+    }
+    let probe: Probe = serde_json::from_slice(&output.stdout)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let Some(stream) = probe.streams.first() else {
+        problems.push("No audio stream".to_string());
+        return Ok(problems);
+    };
+
+    let codec = stream.codec_name.as_deref().unwrap_or("unknown");
+    if codec != expected.format.codec_name() {
+        problems.push(format!(
+            "Codec is {codec} instead of {}",
+            expected.format.codec_name()
+        ));
+    }
+    let channels = stream.channels.unwrap_or(0);
+    if channels != expected.channels {
+        problems.push(format!(
+            "Has {channels} channels instead of {}",
+            expected.channels
+        ));
+    }
+    let sample_rate: u32 = stream
+        .sample_rate
+        .as_deref()
+        .and_then(|rate| rate.parse().ok())
+        .unwrap_or(0);
+    // opus always decodes at 48kHz, the rate it was encoded at is only a hint in the header
+    let opus_rate = matches!(expected.format, Format::Webm | Format::Opus) && sample_rate == 48000;
+    if sample_rate != expected.sample_rate && !opus_rate {
+        problems.push(format!(
+            "Sample rate is {sample_rate} instead of {}",
+            expected.sample_rate
+        ));
+    }
+    let actual_duration: Option<f64> = probe
+        .format
+        .and_then(|container| container.duration)
+        .and_then(|duration| duration.parse().ok());
+    match actual_duration {
+        Some(actual) => {
+            if (actual - duration).abs() * 1000.0 > f64::from(tolerance) {
+                problems.push(format!(
+                    "Duration is {actual:.3}s instead of {duration:.3}s"
+                ));
+            }
+        }
+        None => problems.push("Duration is unknown".to_string()),
+    }
+
+    match max_volume(options, path, duration)? {
+        Some(volume) if volume > SILENCE_THRESHOLD_DB => {}
+        // placeholder and padding sounds are meant to be silent
+        Some(_) if !any_audible(options, &expected.sources, duration)? => {}
+        Some(_) => problems.push("Audio is silent".to_string()),
+        None => problems.push("Could not measure the volume".to_string()),
+    }
+    Ok(problems)
+}
+
+/// Whether any of the sources is louder than silence.
+/// A source that can not be measured counts as audible, so the silent output is still reported.
+fn any_audible(options: &EncodeOptions, sources: &[PathBuf], duration: f64) -> io::Result<bool> {
+    for source in sources {
+        match max_volume(options, source, duration)? {
+            Some(volume) if volume <= SILENCE_THRESHOLD_DB => {}
+            _ => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+/// Loudest sample of a file in dB, `None` when ffmpeg did not report it.
+fn max_volume(options: &EncodeOptions, path: &Path, duration: f64) -> io::Result<Option<f64>> {
+    let mut command = Command::new(&options.ffmpeg);
+    command
+        .arg("-hide_banner")
+        .arg("-i")
+        .arg(path)
+        .arg("-af")
+        .arg("volumedetect")
+        .arg("-f")
+        .arg("null")
+        .arg("-");
+    let output = options.run(&mut command, duration)?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(parse_max_volume(&String::from_utf8_lossy(&output.stderr)))
+}
+
+/// Reads `max_volume: -3.2 dB` from the volumedetect log, `-inf` means all samples are zero.
+fn parse_max_volume(log: &str) -> Option<f64> {
+    let line = log.lines().find(|line| line.contains("max_volume:"))?;
+    let value = line
+        .split("max_volume:")
+        .nth(1)?
+        .trim()
+        .trim_end_matches("dB");
+    match value.trim() {
+        "-inf" => Some(f64::NEG_INFINITY),
+        value => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_volumedetect_output() {
+        let log = "[Parsed_volumedetect_0 @ 0x1] n_samples: 96000\n\
+                   [Parsed_volumedetect_0 @ 0x1] mean_volume: -20.1 dB\n\
+                   [Parsed_volumedetect_0 @ 0x1] max_volume: -3.2 dB\n";
+        assert_eq!(parse_max_volume(log), Some(-3.2));
+        let silent = "[Parsed_volumedetect_0 @ 0x1] max_volume: -inf dB\n";
+        assert_eq!(parse_max_volume(silent), Some(f64::NEG_INFINITY));
+        assert_eq!(parse_max_volume(""), None);
+    }
+}
@@ -0,0 +1,364 @@
+// Keeps encoding the sources that change after the first run.
+// The config and the items stay in memory, a change to a wav file only
+// recreates the item of that file, a change to the config recreates all of them.
+
+use clap::Parser;
+use notify::{Event, EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use crate::{
+    check,
+    config::{self, Config, WatchArgs},
+    discover, encoder,
+    info::{self, Item},
+    lock, naming, sources,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the wait for changes checks whether the user cancelled.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+type Events = Receiver<notify::Result<Event>>;
+
+/// A directory sources are read from, and what they belong to.
+struct SourceDir {
+    canonical: PathBuf,
+    path: PathBuf,
+    package: String,
+    lang: String,
+    /// Whether sounds are inherited from or into this directory, a change to it
+    /// can change the items of other directories.
+    layered: bool,
+}
+
+/// The directories of a package, one per language.
+fn package_dirs(
+    indir: &Path,
+    package: &str,
+    sourcedir: &str,
+    languages: Option<&HashMap<String, String>>,
+) -> Vec<(String, PathBuf)> {
+    let sourcedir_path = indir.join(package).join(sourcedir);
+    match languages {
+        Some(languages) => languages
+            .iter()
+            .map(|(lang, dir)| (lang.clone(), sourcedir_path.join(dir)))
+            .collect(),
+        None => vec![(crate::NO_LANG.to_string(), sourcedir_path)],
+    }
+}
+
+/// The directories `create_items` reads, resolved so they can be compared with event paths.
+fn source_dirs(config: &Config) -> Vec<SourceDir> {
+    let indir = Path::new(&config.indir);
+    let inherited: HashSet<&str> = config
+        .packages
+        .values()
+        .flat_map(|package| &package.inherited_sounds)
+        .map(|layer| layer.package.as_str())
+        .collect();
+    let mut dirs: Vec<SourceDir> = Vec::new();
+    for (package_name, package_config) in &config.packages {
+        let sourcedir = package_config.sourcedir.as_deref().unwrap_or("sounds");
+        let layered = !package_config.inherited_sounds.is_empty()
+            || inherited.contains(package_name.as_str());
+        let languages = package_config.languages.as_ref();
+        let mut langs: Vec<(String, PathBuf, bool)> =
+            package_dirs(indir, package_name, sourcedir, languages)
+                .into_iter()
+                .map(|(lang, path)| (lang, path, layered))
+                .collect();
+        // the extended packages may not be built themselves, but changes to them still count
+        for layer in &package_config.inherited_sounds {
+            let languages = layer.languages.as_ref();
+            for (lang, path) in package_dirs(indir, &layer.package, &layer.sourcedir, languages) {
+                langs.push((lang, path, true));
+            }
+        }
+        for (lang, path, layered) in langs {
+            if let Ok(canonical) = path.canonicalize() {
+                dirs.push(SourceDir {
+                    canonical,
+                    path,
+                    package: package_name.clone(),
+                    lang,
+                    layered,
+                });
+            }
+        }
+    }
+    dirs
+}
+
+/// The innermost source directory `path` is in, with the path of the file relative to it.
+fn find_dir<'a>(dirs: &'a [SourceDir], path: &Path) -> Option<(&'a SourceDir, PathBuf)> {
+    let parent = path.parent()?.canonicalize().ok()?;
+    let dir = dirs
+        .iter()
+        .filter(|dir| parent.starts_with(&dir.canonical))
+        .max_by_key(|dir| dir.canonical.components().count())?;
+    let relative = parent
+        .strip_prefix(&dir.canonical)
+        .ok()?
+        .join(path.file_name()?);
+    Some((dir, relative))
+}
+
+fn create_watcher(
+    poll: bool,
+    paths: &[(PathBuf, RecursiveMode)],
+) -> io::Result<(Box<dyn Watcher>, Events)> {
+    let to_io = |e: notify::Error| io::Error::other(e.to_string());
+    if !poll {
+        let (sender, receiver) = mpsc::channel();
+        let native =
+            RecommendedWatcher::new(sender, notify::Config::default()).and_then(|mut watcher| {
+                for (path, mode) in paths {
+                    watcher.watch(path, *mode)?;
+                }
+                Ok(watcher)
+            });
+        match native {
+            Ok(watcher) => return Ok((Box::new(watcher), receiver)),
+            Err(e) => warn!("File system events are not available, polling instead: {e}"),
+        }
+    }
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = PollWatcher::new(
+        sender,
+        notify::Config::default().with_poll_interval(POLL_INTERVAL),
+    )
+    .map_err(to_io)?;
+    for (path, mode) in paths {
+        watcher.watch(path, *mode).map_err(to_io)?;
+    }
+    Ok((Box::new(watcher), receiver))
+}
+
+/// Waits for a change, then collects changes until none arrive for `debounce`.
+/// Returns the changed paths, or `None` when the watcher stopped or the user cancelled.
+fn next_batch(receiver: &Events, debounce: Duration) -> Option<HashSet<PathBuf>> {
+    let mut paths: HashSet<PathBuf> = HashSet::new();
+    let mut add = |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            if matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) {
+                paths.extend(event.paths);
+            }
+        }
+        Err(e) => warn!("Watch error: {e}"),
+    };
+    loop {
+        if encoder::is_cancelled() {
+            return None;
+        }
+        match receiver.recv_timeout(CANCEL_CHECK_INTERVAL) {
+            Ok(event) => {
+                add(event);
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+    loop {
+        match receiver.recv_timeout(debounce) {
+            Ok(event) => add(event),
+            Err(RecvTimeoutError::Timeout) => break,
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+    Some(paths)
+}
+
+fn reload_config(config_path: &str) -> io::Result<(Config, naming::Template)> {
+    let mut args = config::Args::parse();
+    args.command = None;
+    let declared = Config::load(config_path)?;
+    let mut config = declared.clone();
+    config.resolve_extends()?;
+    let config = config.merge_with_args(args);
+    check::require_valid(&declared, &config)?;
+    let template = naming::Template::from_config(&config)?;
+    Ok((config, template))
+}
+
+/// Recreates the items of the changed wav files in `map`, removing the ones that are gone.
+fn update_items(
+    config: &Config,
+    template: &naming::Template,
+    dirs: &[SourceDir],
+    map: &mut info::Map,
+    paths: &[PathBuf],
+) {
+    let config_formats = config.formats();
+    for path in paths {
+        let Some((dir, relative)) = find_dir(dirs, path) else {
+            continue;
+        };
+        let package_config = &config.packages[&dir.package];
+        let source = dir.path.join(&relative);
+        // files that are ignored or too deep are not sounds of the package
+        let name = match discover::Walk::from_package(&dir.package, package_config) {
+            Ok(walk) => walk.name(&dir.path, &source),
+            Err(e) => {
+                error!("{e}");
+                continue;
+            }
+        };
+        let Some(name) = name else {
+            continue;
+        };
+        let key = source.to_string_lossy().to_string();
+        if !source.is_file() {
+            // the file is gone, so its key is made from the resolved directory
+            let resolved = dir.canonical.join(&relative);
+            if map.remove(&resolved.to_string_lossy()).is_some() {
+                info!("Removed {key}");
+            }
+            continue;
+        }
+        let package_sources =
+            match sources::Sources::new(&dir.package, package_config.sources.as_ref()) {
+                Ok(sources) => sources,
+                Err(e) => {
+                    error!("{e}");
+                    continue;
+                }
+            };
+        let package_formats = package_config.formats(&config_formats);
+        let file = discover::SourceFile {
+            path: source,
+            name,
+            inherited_from: None,
+        };
+        let result = crate::create_item_for_file(
+            &file,
+            &package_sources,
+            package_config,
+            &dir.package,
+            config,
+            template,
+            false,
+            map,
+            &dir.lang,
+            &package_formats,
+        );
+        match result {
+            Some(Ok(item)) => {
+                info!("Changed {key}");
+                map.set(key, item);
+            }
+            Some(Err(e)) => error!("{e}"),
+            None => {}
+        }
+    }
+}
+
+/// Watches the input directory and the config file and encodes what changes, until stopped.
+pub fn run(
+    mut config: Config,
+    config_path: &str,
+    args: &WatchArgs,
+    mut map: info::Map,
+) -> io::Result<()> {
+    let debounce = Duration::from_millis(args.debounce);
+    let indir = Path::new(&config.indir).canonicalize()?;
+    let config_file = Path::new(config_path).canonicalize()?;
+    let mut paths = vec![(indir.clone(), RecursiveMode::Recursive)];
+    // editors often replace the file, so its directory is watched instead
+    if let Some(config_dir) = config_file.parent() {
+        if !config_dir.starts_with(&indir) {
+            paths.push((config_dir.to_path_buf(), RecursiveMode::NonRecursive));
+        }
+    }
+    let (_watcher, receiver) = create_watcher(args.poll.unwrap_or(false), &paths)?;
+    let mut dirs = source_dirs(&config);
+    let mut template = naming::Template::from_config(&config)?;
+    success!("Watching {} for changes", indir.to_string_lossy());
+
+    while let Some(changed) = next_batch(&receiver, debounce) {
+        let config_changed = changed.contains(&config_file);
+        let wavs: Vec<PathBuf> = changed
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|extension| extension == "wav"))
+            .collect();
+        if !config_changed && wavs.is_empty() {
+            continue;
+        }
+        // held for this batch only, a build started while watching waits for it, and the
+        // other way around, changes that come in while another run has the lock wait for it
+        let _lock = match lock::RunLock::acquire(&config.cache_dir(), true) {
+            Ok(lock) => lock,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => break,
+            Err(e) => {
+                error!("{e}");
+                continue;
+            }
+        };
+
+        let layered = wavs
+            .iter()
+            .any(|path| find_dir(&dirs, path).is_some_and(|(dir, _)| dir.layered));
+        let items: Vec<Item> = if config_changed {
+            match reload_config(config_path) {
+                Ok((reloaded, reloaded_template)) => {
+                    info!("Config changed, recreating all items");
+                    if reloaded.indir != config.indir {
+                        warn!("The input directory changed, restart scode watch to watch it");
+                    }
+                    config = reloaded;
+                    template = reloaded_template;
+                    dirs = source_dirs(&config);
+                    match crate::create_items(&config, &map, false) {
+                        Ok((items, _)) => items,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => break,
+                        Err(e) => {
+                            error!("{e}");
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Could not reload the config, keeping the previous one: {e}");
+                    continue;
+                }
+            }
+        } else if layered {
+            info!("Inherited sounds changed, recreating all items");
+            match crate::create_items(&config, &map, false) {
+                Ok((items, _)) => items,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => break,
+                Err(e) => {
+                    error!("{e}");
+                    continue;
+                }
+            }
+        } else {
+            update_items(&config, &template, &dirs, &mut map, &wavs);
+            map.value
+                .values()
+                .filter(|item| config.packages.contains_key(&item.package))
+                .cloned()
+                .collect()
+        };
+
+        match crate::build(&config, items) {
+            Ok(items) => {
+                map = info::Map::from_vec(items);
+                success!("Up to date, watching for changes");
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => break,
+            Err(e) => error!("{e}"),
+        }
+    }
+    Ok(())
+}
@@ -1,6 +1,9 @@
-use std::io::{self, Cursor, Read};
 use byteorder::{LittleEndian, ReadBytesExt};
 use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor, Read};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FormatChunk {
@@ -10,18 +13,32 @@ pub struct FormatChunk {
     pub byte_rate: u32,
     pub block_align: u16,
     pub bits_per_sample: u16,
+    /// Speaker positions from a `WAVE_FORMAT_EXTENSIBLE` header, 0 when not present.
+    pub channel_mask: u32,
 }
 
 impl FormatChunk {
-    fn from_buffer(cursor: &mut Cursor<&[u8]>) -> io::Result<Self> {
-        Ok(FormatChunk {
+    fn from_buffer(cursor: &mut Cursor<&[u8]>, chunk_size: u32) -> io::Result<Self> {
+        let start = cursor.position();
+        let mut format = FormatChunk {
             audio_format: cursor.read_u16::<LittleEndian>()?,
             num_channels: cursor.read_u16::<LittleEndian>()?,
             sample_rate: cursor.read_u32::<LittleEndian>()?,
             byte_rate: cursor.read_u32::<LittleEndian>()?,
             block_align: cursor.read_u16::<LittleEndian>()?,
             bits_per_sample: cursor.read_u16::<LittleEndian>()?,
-        })
+            channel_mask: 0,
+        };
+        if format.audio_format == FORMAT_EXTENSIBLE && chunk_size >= 40 {
+            let _extension_size = cursor.read_u16::<LittleEndian>()?;
+            let _valid_bits_per_sample = cursor.read_u16::<LittleEndian>()?;
+            format.channel_mask = cursor.read_u32::<LittleEndian>()?;
+            // the first two bytes of the sub format guid is the actual format code
+            format.audio_format = cursor.read_u16::<LittleEndian>()?;
+        }
+        // skip any extension bytes we do not care about, chunks are padded to an even size
+        cursor.set_position(start + u64::from(chunk_size) + u64::from(chunk_size % 2));
+        Ok(format)
     }
 }
 
@@ -66,10 +83,10 @@ impl Data {
             let chunk_size = cursor.read_u32::<LittleEndian>()?;
             match &chunk_id {
                 b"fmt " => {
-                    format = Some(FormatChunk::from_buffer(&mut cursor)?);
+                    format = Some(FormatChunk::from_buffer(&mut cursor, chunk_size)?);
                     match &format {
                         Some(f) => {
-                            if f.audio_format != 1 {
+                            if f.audio_format != FORMAT_PCM {
                                 return Err(io::Error::new(
                                     io::ErrorKind::InvalidData,
                                     format!("Audio format {} is not PCM", f.audio_format),
@@ -91,7 +108,9 @@ impl Data {
                 }
                 _ => {
                     // Skip over the chunk's content if it's not "fmt " or "data"
-                   cursor.set_position(cursor.position() + u64::from(chunk_size));
+                    cursor.set_position(
+                        cursor.position() + u64::from(chunk_size) + u64::from(chunk_size % 2),
+                    );
                 }
             }
         }
@@ -122,7 +141,7 @@ impl Data {
         Ok(Data {
             format,
             num_samples,
-            duration
+            duration,
         })
     }
 }